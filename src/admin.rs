@@ -0,0 +1,262 @@
+//! A dashboard for running the image host from a browser, at `/admin`.
+//! Logging in stores an admin API key in a private cookie, which only works
+//! for the dashboard.
+
+use crate::auth::{AdminSession, KEY_COOKIE};
+use crate::config::Config;
+use crate::error::UploadError;
+use crate::util::ImageId;
+use crate::{db, reencode, util};
+use base64::{engine::general_purpose, Engine};
+use bson::{doc, Document};
+use rocket::form::{Form, FromForm};
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::request::FromParam;
+use rocket::response::{status, Redirect};
+use rocket::serde::Serialize;
+use rocket::{Route, State};
+use rocket_dyn_templates::{context, Template};
+use tracing::error;
+
+/// How many images are shown in each list on the dashboard.
+const LIST_LENGTH: i64 = 24;
+/// Images that will expire within this long are shown on the dashboard.
+const EXPIRING_SOON_MILLIS: i64 = 30 * 24 * 60 * 60 * 1000;
+
+/// An image as it's shown on the dashboard.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct DashboardImage {
+    id: String,
+    thumbnail_b64: String,
+    thumbnail_content_type: String,
+    content_type: String,
+    width: i32,
+    height: i32,
+    optim_level: i32,
+    /// The date it was uploaded, like 2023-01-02
+    date: String,
+    /// The date it was last viewed, like 2023-01-02
+    last_seen: String,
+    pinned: bool,
+    disabled: bool,
+}
+
+impl DashboardImage {
+    fn from_doc(image_doc: &Document) -> DashboardImage {
+        let day = |key: &str| {
+            image_doc
+                .get_datetime(key)
+                .ok()
+                .and_then(|date| date.try_to_rfc3339_string().ok())
+                .map(|date| date.chars().take(10).collect())
+                .unwrap_or_default()
+        };
+        DashboardImage {
+            id: image_doc.get_str("_id").unwrap_or_default().to_string(),
+            thumbnail_b64: image_doc
+                .get_binary_generic("thumbnail_data")
+                .map(|data| general_purpose::STANDARD.encode(data))
+                .unwrap_or_default(),
+            thumbnail_content_type: image_doc
+                .get_str("thumbnail_content_type")
+                .unwrap_or_default()
+                .to_string(),
+            content_type: image_doc
+                .get_str("content_type")
+                .unwrap_or_default()
+                .to_string(),
+            width: image_doc.get_i32("width").unwrap_or_default(),
+            height: image_doc.get_i32("height").unwrap_or_default(),
+            optim_level: image_doc.get_i32("optim_level").unwrap_or_default(),
+            date: day("date"),
+            last_seen: day("last_seen"),
+            pinned: image_doc.get_bool("pinned").unwrap_or(false),
+            disabled: image_doc.get_bool("disabled").unwrap_or(false),
+        }
+    }
+}
+
+#[get("/admin")]
+async fn dashboard_route(
+    admin: Option<AdminSession>,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+) -> Result<Template, UploadError> {
+    let Some(admin) = admin else {
+        return Ok(Template::render(
+            "admin_login",
+            context! { host: &config.host },
+        ));
+    };
+
+    let recent =
+        db::find_images_without_data(&collections.images, doc! {}, doc! {"date": -1}, LIST_LENGTH)
+            .await?;
+    let expires_before = bson::DateTime::from_millis(
+        bson::DateTime::now().timestamp_millis() - config.images.expiry_millis()
+            + EXPIRING_SOON_MILLIS,
+    );
+    let expiring = db::find_images_without_data(
+        &collections.images,
+        doc! {"last_seen": {"$lt": expires_before}, "pinned": {"$ne": true}},
+        doc! {"last_seen": 1},
+        LIST_LENGTH,
+    )
+    .await?;
+    let expiring_count = db::count_images(
+        &collections.images,
+        doc! {"last_seen": {"$lt": expires_before}, "pinned": {"$ne": true}},
+    )
+    .await?;
+    let backlog = db::count_images(&collections.images, doc! {"optim_level": 0}).await?;
+    let storage = db::get_storage_stats(&collections.images).await?;
+
+    Ok(Template::render(
+        "admin",
+        context! {
+            host: &config.host,
+            admin_name: &admin.0.name,
+            recent: recent.iter().map(DashboardImage::from_doc).collect::<Vec<_>>(),
+            expiring: expiring.iter().map(DashboardImage::from_doc).collect::<Vec<_>>(),
+            expiring_count: expiring_count,
+            backlog: backlog,
+            total_count: storage.iter().map(|row| row.count).sum::<i64>(),
+            total_bytes: storage.iter().map(|row| row.bytes + row.thumbnail_bytes).sum::<i64>(),
+            storage: storage,
+        },
+    ))
+}
+
+#[derive(FromForm)]
+struct LoginForm<'r> {
+    key: &'r str,
+}
+
+#[post("/admin/login", data = "<form>")]
+async fn login_route(
+    form: Form<LoginForm<'_>>,
+    cookies: &CookieJar<'_>,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+) -> Result<Result<Redirect, status::Custom<Template>>, UploadError> {
+    let key = form.key.trim();
+    let key_doc = db::get_api_key(&collections.keys, &util::sha256_hex(key.as_bytes())).await?;
+    if !key_doc.is_some_and(|key_doc| key_doc.get_bool("admin").unwrap_or(false)) {
+        return Ok(Err(status::Custom(
+            Status::Unauthorized,
+            Template::render(
+                "admin_login",
+                context! { host: &config.host, error: "That isn't an admin API key." },
+            ),
+        )));
+    }
+
+    cookies.add_private(
+        Cookie::build(KEY_COOKIE, key.to_string())
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Strict)
+            .path("/")
+            .finish(),
+    );
+    Ok(Ok(Redirect::to("/admin")))
+}
+
+#[post("/admin/logout")]
+fn logout_route(cookies: &CookieJar<'_>) -> Redirect {
+    cookies.remove_private(Cookie::build(KEY_COOKIE, "").path("/").finish());
+    Redirect::to("/admin")
+}
+
+/// The buttons next to each image on the dashboard.
+#[derive(Clone, Copy)]
+enum ImageAction {
+    Delete,
+    /// Encode it again with the settings for its optimization level
+    Reoptimize,
+    /// Keep it from expiring
+    Pin,
+    Unpin,
+}
+
+impl<'a> FromParam<'a> for ImageAction {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        match param {
+            "delete" => Ok(ImageAction::Delete),
+            "reoptimize" => Ok(ImageAction::Reoptimize),
+            "pin" => Ok(ImageAction::Pin),
+            "unpin" => Ok(ImageAction::Unpin),
+            _ => Err(param),
+        }
+    }
+}
+
+#[post("/admin/images/<id>/<action>")]
+async fn image_action_route(
+    id: &str,
+    action: ImageAction,
+    admin: AdminSession,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+) -> Result<Redirect, UploadError> {
+    let image_id = ImageId(id.to_string());
+    let (image_exists, action_name) = match action {
+        ImageAction::Delete => (
+            db::delete_image(&collections.images, &image_id).await?,
+            "delete",
+        ),
+        ImageAction::Reoptimize => {
+            if db::get_image_metadata(&collections.images, id)
+                .await?
+                .is_none()
+            {
+                return Err(UploadError::NotFound("No image found".to_string()));
+            }
+            let owned_images_collection = collections.images.clone();
+            let owned_image_id = image_id.clone();
+            let owned_image_config = config.images.clone();
+            // this can take a while, so it happens in the background
+            tokio::spawn(async move {
+                if let Err(e) = reencode::reencode_image(
+                    &owned_images_collection,
+                    &owned_image_id,
+                    false,
+                    false,
+                    &owned_image_config,
+                )
+                .await
+                {
+                    error!(image_id = %owned_image_id, error = %e, "Error re-optimizing image");
+                }
+            });
+            (true, "reoptimize")
+        }
+        ImageAction::Pin => (
+            db::set_image_pinned(&collections.images, id, true).await?,
+            "pin",
+        ),
+        ImageAction::Unpin => (
+            db::set_image_pinned(&collections.images, id, false).await?,
+            "unpin",
+        ),
+    };
+    if !image_exists {
+        return Err(UploadError::NotFound("No image found".to_string()));
+    }
+
+    db::insert_audit_log_entry(&collections.audit_log, &admin.0.name, action_name, id, None)
+        .await?;
+    Ok(Redirect::to("/admin"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        dashboard_route,
+        login_route,
+        logout_route,
+        image_action_route
+    ]
+}
@@ -0,0 +1,83 @@
+//! Albums are groups of images that were uploaded together, with a page for
+//! viewing all of them.
+
+use crate::config::Config;
+use crate::visibility::Visibility;
+use crate::{db, DocumentJson};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{Route, State};
+use rocket_dyn_templates::{context, Template};
+
+/// The data returned from the /json/a/ route.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AlbumJson {
+    id: String,
+    title: Option<String>,
+    /// The images in the order they were uploaded
+    images: Vec<DocumentJson>,
+}
+
+/// Get the album with its images, in order.
+async fn get_album_with_images(
+    collections: &db::Collections,
+    id: &str,
+) -> Result<Option<AlbumJson>, String> {
+    let Some(album_doc) = db::get_album(&collections.albums, id)
+        .await
+        .map_err(|e| e.to_string())?
+    else {
+        return Ok(None);
+    };
+    let image_ids: Vec<String> = album_doc
+        .get_array("images")
+        .map_err(|e| e.to_string())?
+        .iter()
+        .filter_map(|id| id.as_str().map(|id| id.to_string()))
+        .collect();
+    let image_docs = db::get_images_without_data(&collections.images, &image_ids)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(Some(AlbumJson {
+        id: id.to_string(),
+        title: album_doc.get_str("title").ok().map(|t| t.to_string()),
+        // albums aren't signed, so private images in them stay hidden
+        images: image_docs
+            .iter()
+            .filter(|image_doc| Visibility::from_doc(image_doc) != Visibility::Private)
+            .map(DocumentJson::from_doc)
+            .collect::<Result<_, _>>()?,
+    }))
+}
+
+#[get("/a/<id>")]
+async fn album_page_route(
+    id: &str,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+) -> Result<Option<Template>, String> {
+    let Some(album) = get_album_with_images(collections, id).await? else {
+        return Ok(None);
+    };
+    Ok(Some(Template::render(
+        "album",
+        context! {
+            host: &config.host,
+            album: album,
+        },
+    )))
+}
+
+#[get("/json/a/<id>")]
+async fn album_json_route(
+    id: &str,
+    collections: &State<db::Collections>,
+) -> Result<Option<Json<AlbumJson>>, String> {
+    Ok(get_album_with_images(collections, id).await?.map(Json))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![album_page_route, album_json_route]
+}
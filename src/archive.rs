@@ -0,0 +1,481 @@
+//! Export images to a portable tar archive and import them again, for backups
+//! and moving images between instances.
+//!
+//! The archive starts with `manifest.ndjson`, which has one line of metadata
+//! per image, followed by `images/<id>.<ext>` and `thumbnails/<id>.<ext>` for
+//! each image.
+
+use crate::background_optimization::decode_image_doc;
+use crate::config::BlocklistConfig;
+use crate::error::UploadError;
+use crate::util::{self, ImageId};
+use crate::{blocklist, db, similar};
+use bson::{doc, Bson, Document};
+use futures::stream::TryStreamExt;
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use tokio::sync::mpsc;
+use tokio::task;
+
+const MANIFEST_PATH: &str = "manifest.ndjson";
+
+/// The metadata for one image in the archive manifest.
+#[derive(Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub id: String,
+    pub content_type: String,
+    pub thumbnail_content_type: String,
+    pub width: u32,
+    pub height: u32,
+    /// When the image was uploaded, in RFC 3339
+    pub date: String,
+    /// When the image was last viewed, in RFC 3339
+    pub last_seen: String,
+    pub optim_level: u8,
+    /// The path of the image in the archive
+    pub data: String,
+    /// The path of the thumbnail in the archive
+    pub thumbnail: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub average_color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dhash: Option<i64>,
+    /// The SHA-256 hash of the file that was originally uploaded, in hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// `public`, `unlisted` or `private`, images from before there were
+    /// visibilities don't have one and are public
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<String>,
+    /// The id of the API key that uploaded the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_id: Option<String>,
+    /// Whether a moderator disabled the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disabled: Option<bool>,
+}
+
+impl ManifestEntry {
+    fn from_doc(image_doc: &Document) -> Result<ManifestEntry, String> {
+        let id = image_doc.get_str("_id").map_err(|e| e.to_string())?;
+        let content_type = image_doc
+            .get_str("content_type")
+            .map_err(|e| e.to_string())?;
+        let thumbnail_content_type = image_doc
+            .get_str("thumbnail_content_type")
+            .map_err(|e| e.to_string())?;
+        let date = image_doc
+            .get_datetime("date")
+            .map_err(|e| e.to_string())?
+            .try_to_rfc3339_string()
+            .map_err(|e| e.to_string())?;
+        let last_seen = image_doc
+            .get_datetime("last_seen")
+            .map_err(|e| e.to_string())?
+            .try_to_rfc3339_string()
+            .map_err(|e| e.to_string())?;
+
+        Ok(ManifestEntry {
+            id: id.to_string(),
+            content_type: content_type.to_string(),
+            thumbnail_content_type: thumbnail_content_type.to_string(),
+            width: image_doc.get_i32("width").unwrap_or(0) as u32,
+            height: image_doc.get_i32("height").unwrap_or(0) as u32,
+            date,
+            last_seen,
+            optim_level: image_doc.get_i32("optim_level").unwrap_or(0) as u8,
+            data: format!("images/{}.{}", id, extension_for(content_type)),
+            thumbnail: format!(
+                "thumbnails/{}.{}",
+                id,
+                extension_for(thumbnail_content_type)
+            ),
+            blurhash: image_doc.get_str("blurhash").ok().map(|s| s.to_string()),
+            average_color: image_doc
+                .get_str("average_color")
+                .ok()
+                .map(|s| s.to_string()),
+            dhash: image_doc.get_i64("dhash").ok(),
+            sha256: image_doc.get_str("sha256").ok().map(|s| s.to_string()),
+            visibility: image_doc.get_str("visibility").ok().map(|s| s.to_string()),
+            api_key_id: image_doc.get_str("api_key_id").ok().map(|s| s.to_string()),
+            disabled: image_doc.get_bool("disabled").ok(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ImportStats {
+    pub imported: u64,
+    /// The ids that already existed in the database and were skipped
+    pub conflicts: Vec<String>,
+    /// The ids in the manifest that didn't have their files in the archive
+    pub incomplete: Vec<String>,
+    /// The ids that were skipped because the image is on the blocklist
+    pub blocked: Vec<String>,
+}
+
+/// The files of an image that we've read from the archive so far.
+#[derive(Default)]
+struct PendingImage {
+    data: Option<Vec<u8>>,
+    thumbnail_data: Option<Vec<u8>>,
+}
+
+/// The file extension used for a content type in the archive.
+fn extension_for(content_type: &str) -> &'static str {
+    ImageFormat::from_mime_type(content_type)
+        .and_then(|f| f.extensions_str().first().copied())
+        .unwrap_or("bin")
+}
+
+fn append_file(
+    builder: &mut tar::Builder<File>,
+    path: &str,
+    data: &[u8],
+) -> Result<(), std::io::Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    );
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)
+}
+
+/// Write every image to a tar archive at `path`, returning how many were
+/// exported.
+pub async fn export_archive(collections: &db::Collections, path: &Path) -> Result<u64, String> {
+    // the manifest goes first so importing can be done in one pass, which
+    // means we have to look at the metadata before the images
+    let metadata_docs: Vec<Document> = collections
+        .images
+        .find(
+            None,
+            mongodb::options::FindOptions::builder()
+                .projection(doc! {"data": 0, "thumbnail_data": 0})
+                .sort(doc! {"_id": 1})
+                .build(),
+        )
+        .await
+        .map_err(|e| e.to_string())?
+        .try_collect()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut manifest = String::new();
+    let mut entries = HashMap::new();
+    for image_doc in &metadata_docs {
+        match ManifestEntry::from_doc(image_doc) {
+            Ok(entry) => {
+                manifest.push_str(&serde_json::to_string(&entry).map_err(|e| e.to_string())?);
+                manifest.push('\n');
+                entries.insert(entry.id.clone(), entry);
+            }
+            Err(e) => println!("Skipping {:?}: {}", image_doc.get("_id"), e),
+        }
+    }
+
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut builder = tar::Builder::new(file);
+    append_file(&mut builder, MANIFEST_PATH, manifest.as_bytes()).map_err(|e| e.to_string())?;
+
+    let mut images_cursor = collections
+        .images
+        .find(
+            None,
+            mongodb::options::FindOptions::builder()
+                .sort(doc! {"_id": 1})
+                .build(),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut exported = HashSet::new();
+    while let Some(image_doc) = images_cursor.try_next().await.map_err(|e| e.to_string())? {
+        // images uploaded after we wrote the manifest are left out
+        let Some(entry) = image_doc.get_str("_id").ok().and_then(|id| entries.get(id)) else {
+            continue;
+        };
+        let data = image_doc
+            .get_binary_generic("data")
+            .map_err(|e| e.to_string())?;
+        let thumbnail_data = image_doc
+            .get_binary_generic("thumbnail_data")
+            .map_err(|e| e.to_string())?;
+        append_file(&mut builder, &entry.data, data).map_err(|e| e.to_string())?;
+        append_file(&mut builder, &entry.thumbnail, thumbnail_data).map_err(|e| e.to_string())?;
+        exported.insert(entry.id.clone());
+    }
+    builder.finish().map_err(|e| e.to_string())?;
+
+    for id in entries.keys().filter(|id| !exported.contains(*id)) {
+        println!("{} was deleted while exporting", id);
+    }
+
+    Ok(exported.len() as u64)
+}
+
+/// Insert every image from a tar archive at `path`, keeping their ids. Images
+/// whose id is already taken or that are on the blocklist are skipped.
+pub async fn import_archive(
+    collections: &db::Collections,
+    blocklist_config: &BlocklistConfig,
+    path: &Path,
+) -> Result<ImportStats, String> {
+    // tar reading is blocking, so it happens on another thread that sends us
+    // the files one at a time
+    let (sender, mut receiver) = mpsc::channel(4);
+    let path = path.to_path_buf();
+    task::spawn_blocking(move || {
+        if let Err(e) = read_archive_files(&path, &sender) {
+            sender.blocking_send(Err(e)).ok();
+        }
+    });
+
+    let (manifest_path, manifest) = receiver.recv().await.ok_or("The archive is empty")??;
+    if manifest_path != MANIFEST_PATH {
+        return Err(format!("The archive must start with {}", MANIFEST_PATH));
+    }
+
+    // map the paths of files in the archive to their manifest entry
+    let mut entries_by_path: HashMap<String, &ManifestEntry> = HashMap::new();
+    let manifest: Vec<ManifestEntry> = String::from_utf8_lossy(&manifest)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Invalid manifest: {}", e))?;
+    for entry in &manifest {
+        entries_by_path.insert(entry.data.clone(), entry);
+        entries_by_path.insert(entry.thumbnail.clone(), entry);
+    }
+
+    let mut stats = ImportStats::default();
+    // the image and thumbnail are next to each other in the archive, so this
+    // only ever holds one image at a time for archives made by export_archive
+    let mut pending: HashMap<String, PendingImage> = HashMap::new();
+    let mut done = HashSet::new();
+    while let Some(file) = receiver.recv().await {
+        let (entry_path, data) = file?;
+        let Some(entry) = entries_by_path.get(&entry_path) else {
+            println!("Ignoring {}, it's not in the manifest", entry_path);
+            continue;
+        };
+
+        let files = pending.entry(entry.id.clone()).or_default();
+        if entry_path == entry.data {
+            files.data = Some(data);
+        } else {
+            files.thumbnail_data = Some(data);
+        }
+        if let PendingImage {
+            data: Some(_),
+            thumbnail_data: Some(_),
+        } = files
+        {
+            let files = pending.remove(&entry.id).unwrap();
+            let mut image_doc =
+                archived_image_doc(entry, files.data.unwrap(), files.thumbnail_data.unwrap())?;
+            done.insert(entry.id.clone());
+            if is_blocked(&collections.blocklist, blocklist_config, &mut image_doc).await? {
+                println!("{} is on the blocklist, skipping", entry.id);
+                stats.blocked.push(entry.id.clone());
+                continue;
+            }
+            let inserted = db::insert_archived_image(&collections.images, &image_doc)
+                .await
+                .map_err(|e| e.to_string())?;
+            if inserted {
+                stats.imported += 1;
+            } else {
+                println!("{} already exists, skipping", entry.id);
+                stats.conflicts.push(entry.id.clone());
+            }
+        }
+    }
+
+    stats.incomplete = manifest
+        .iter()
+        .filter(|entry| !done.contains(&entry.id))
+        .map(|entry| entry.id.clone())
+        .collect();
+    for id in &stats.incomplete {
+        println!("{} is missing its image or thumbnail", id);
+    }
+
+    Ok(stats)
+}
+
+/// Whether an image from the archive is on the blocklist, checked the same way
+/// as uploads. Images without a perceptual hash get one, so they can be found
+/// by it later too.
+async fn is_blocked(
+    blocklist_collection: &mongodb::Collection<Document>,
+    config: &BlocklistConfig,
+    image_doc: &mut Document,
+) -> Result<bool, String> {
+    let data = image_doc
+        .get_binary_generic("data")
+        .map_err(|e| e.to_string())?;
+    // images from before the uploaded file's hash was saved were blocked by
+    // the hash of their stored data
+    let mut sha256s = vec![util::sha256_hex(data)];
+    if let Ok(sha256) = image_doc.get_str("sha256") {
+        sha256s.push(sha256.to_string());
+    }
+    if similar::hash_from_doc(image_doc).is_none() {
+        // if it can't be decoded it's only checked by its hashes
+        if let Ok(image) = decode_image_doc(image_doc).await {
+            image_doc.insert("dhash", similar::dhash(&image) as i64);
+        }
+    }
+
+    let checked = async {
+        for sha256 in &sha256s {
+            blocklist::check_sha256(blocklist_collection, config, sha256).await?;
+        }
+        if let Some(perceptual_hash) = similar::hash_from_doc(image_doc) {
+            blocklist::check_perceptual_hash(blocklist_collection, config, perceptual_hash).await?;
+        }
+        Ok(())
+    }
+    .await;
+    match checked {
+        Ok(()) => Ok(false),
+        Err(UploadError::UnavailableForLegalReasons(_)) => Ok(true),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Read every file in the tar archive and send their paths and contents.
+fn read_archive_files(
+    path: &Path,
+    sender: &mpsc::Sender<Result<(String, Vec<u8>), String>>,
+) -> Result<(), String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut archive = tar::Archive::new(file);
+    for archive_entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut archive_entry = archive_entry.map_err(|e| e.to_string())?;
+        let entry_path = archive_entry
+            .path()
+            .map_err(|e| e.to_string())?
+            .to_string_lossy()
+            .to_string();
+        let mut data = Vec::new();
+        archive_entry
+            .read_to_end(&mut data)
+            .map_err(|e| e.to_string())?;
+        if sender.blocking_send(Ok((entry_path, data))).is_err() {
+            // the receiver stopped caring, probably because of an error
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Create the document for an image from the archive.
+fn archived_image_doc(
+    entry: &ManifestEntry,
+    data: Vec<u8>,
+    thumbnail_data: Vec<u8>,
+) -> Result<Document, String> {
+    let date = bson::DateTime::parse_rfc3339_str(&entry.date).map_err(|e| e.to_string())?;
+    let last_seen =
+        bson::DateTime::parse_rfc3339_str(&entry.last_seen).map_err(|e| e.to_string())?;
+    let mut image_doc = doc! {
+        "_id": ImageId(entry.id.clone()),
+        "date": date,
+        "last_seen": last_seen,
+        "data": Bson::Binary(bson::Binary { subtype: bson::spec::BinarySubtype::Generic, bytes: data }),
+        "content_type": &entry.content_type,
+        "width": entry.width,
+        "height": entry.height,
+        "thumbnail_data": Bson::Binary(bson::Binary { subtype: bson::spec::BinarySubtype::Generic, bytes: thumbnail_data }),
+        "thumbnail_content_type": &entry.thumbnail_content_type,
+        "optim_level": entry.optim_level as i32,
+    };
+    if let Some(blurhash) = &entry.blurhash {
+        image_doc.insert("blurhash", blurhash);
+    }
+    if let Some(average_color) = &entry.average_color {
+        image_doc.insert("average_color", average_color);
+    }
+    if let Some(dhash) = entry.dhash {
+        image_doc.insert("dhash", dhash);
+    }
+    if let Some(sha256) = &entry.sha256 {
+        image_doc.insert("sha256", sha256);
+    }
+    if let Some(visibility) = &entry.visibility {
+        image_doc.insert("visibility", visibility);
+    }
+    if let Some(api_key_id) = &entry.api_key_id {
+        image_doc.insert("api_key_id", api_key_id);
+    }
+    if let Some(disabled) = entry.disabled {
+        image_doc.insert("disabled", disabled);
+    }
+    Ok(image_doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn manifest_entry_round_trips() {
+        let date = bson::DateTime::parse_rfc3339_str("2023-01-02T03:04:05Z").unwrap();
+        let image_doc = doc! {
+            "_id": "bcdfg",
+            "date": date,
+            "last_seen": date,
+            "content_type": "image/webp",
+            "width": 640,
+            "height": 480,
+            "thumbnail_content_type": "image/png",
+            "optim_level": 1,
+            "blurhash": "LEHV6nWB2yk8pyo0adR*.7kCMdnj",
+            "average_color": "#ff1493",
+            "dhash": -42i64,
+            "sha256": "ab".repeat(32),
+            "visibility": "private",
+            "api_key_id": "key-id",
+            "disabled": true,
+        };
+        let entry = ManifestEntry::from_doc(&image_doc).unwrap();
+        assert_eq!(entry.data, "images/bcdfg.webp");
+        assert_eq!(entry.thumbnail, "thumbnails/bcdfg.png");
+
+        let line = serde_json::to_string(&entry).unwrap();
+        let entry: ManifestEntry = serde_json::from_str(&line).unwrap();
+        let imported_doc = archived_image_doc(&entry, vec![1], vec![2]).unwrap();
+        for key in [
+            "_id",
+            "date",
+            "last_seen",
+            "content_type",
+            "width",
+            "height",
+            "thumbnail_content_type",
+            "optim_level",
+            "blurhash",
+            "average_color",
+            "dhash",
+            "sha256",
+            "visibility",
+            "api_key_id",
+            "disabled",
+        ] {
+            assert_eq!(imported_doc.get(key), image_doc.get(key), "{}", key);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Authenticating requests with the API keys made by `keys create`.
+
+use crate::{db, util};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+/// The private cookie the admin dashboard keeps the API key in after logging
+/// in. It's encrypted with Rocket's `secret_key`.
+pub const KEY_COOKIE: &str = "api_key";
+
+/// A request guard for requests with a valid API key, sent either as
+/// `Authorization: Bearer <key>` or `X-API-Key: <key>`.
+pub struct ApiKey {
+    /// The SHA-256 hash of the key, which is its id in the database
+    pub id: String,
+    /// The name the key was created with
+    pub name: String,
+    pub admin: bool,
+}
+
+/// A request guard for requests with a valid admin API key.
+pub struct AdminKey(pub ApiKey);
+
+/// A request guard for the admin dashboard, which takes an admin API key like
+/// [`AdminKey`] or from the [`KEY_COOKIE`] cookie set by logging in.
+pub struct AdminSession(pub ApiKey);
+
+/// Look up the API key in the database.
+async fn find_key(req: &Request<'_>, key: &str) -> Outcome<ApiKey, String> {
+    let Some(collections) = req.rocket().state::<db::Collections>() else {
+        return Outcome::Failure((
+            Status::InternalServerError,
+            "Database isn't set up".to_string(),
+        ));
+    };
+    match db::get_api_key(&collections.keys, &util::sha256_hex(key.trim().as_bytes())).await {
+        Ok(Some(key_doc)) => Outcome::Success(ApiKey {
+            id: key_doc.get_str("_id").unwrap_or_default().to_string(),
+            name: key_doc.get_str("name").unwrap_or_default().to_string(),
+            admin: key_doc.get_bool("admin").unwrap_or(false),
+        }),
+        Ok(None) => Outcome::Failure((Status::Unauthorized, "Invalid API key".to_string())),
+        Err(e) => Outcome::Failure((Status::InternalServerError, e.to_string())),
+    }
+}
+
+/// Only let admin keys through.
+fn require_admin(outcome: Outcome<ApiKey, String>) -> Outcome<ApiKey, String> {
+    match outcome {
+        Outcome::Success(key) if !key.admin => Outcome::Failure((
+            Status::Forbidden,
+            "This API key isn't an admin key".to_string(),
+        )),
+        outcome => outcome,
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let key = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| req.headers().get_one("X-API-Key"));
+        let Some(key) = key else {
+            return Outcome::Failure((Status::Unauthorized, "Missing API key".to_string()));
+        };
+        find_key(req, key).await
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminKey {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        require_admin(ApiKey::from_request(req).await).map(AdminKey)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminSession {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let outcome = match req.cookies().get_private(KEY_COOKIE) {
+            Some(cookie) => find_key(req, cookie.value()).await,
+            None => ApiKey::from_request(req).await,
+        };
+        require_admin(outcome).map(AdminSession)
+    }
+}
+
+#[cfg(test)]
+// see the comment on `mod albums` in main.rs
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use rocket::http::Cookie;
+    use rocket::local::blocking::Client;
+
+    #[get("/")]
+    fn authenticated(api_key: ApiKey) -> String {
+        api_key.name
+    }
+
+    #[test]
+    fn api_keys_arent_taken_from_cookies() {
+        let client = Client::tracked(rocket::build().mount("/", routes![authenticated])).unwrap();
+        for cookie in [
+            client.get("/").cookie(Cookie::new(KEY_COOKIE, "key")),
+            client
+                .get("/")
+                .private_cookie(Cookie::new(KEY_COOKIE, "key")),
+        ] {
+            assert_eq!(cookie.dispatch().status(), Status::Unauthorized);
+        }
+    }
+}
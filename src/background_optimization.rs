@@ -3,21 +3,83 @@
 
 use std::io::Cursor;
 
-use crate::encoding::{from_image, FromImageOptions};
-use crate::{db, util};
+use crate::config::ImageConfig;
+use crate::encoding::{decode_with_limits, from_image, FromImageOptions};
+use crate::{db, metrics, util};
 use bson::Document;
+use futures::future::join_all;
 use futures::join;
-use futures::stream::TryStreamExt;
 use image::io::Reader;
+use image::DynamicImage;
 use mongodb::bson::doc;
 use mongodb::Collection;
-use tokio::task;
+use tracing::{error, info, instrument};
 use util::ImageId;
 
+/// The options used for encoding the full image at the given optimization level.
+pub fn image_options_for_level(optim_level: u8, config: &ImageConfig) -> FromImageOptions {
+    match optim_level {
+        0 => FromImageOptions {
+            webp_quality: config.webp_quality,
+            ..FromImageOptions::default()
+        },
+        _ => FromImageOptions {
+            optimize_png: true,
+            max_size: Some(config.optimized_max_size),
+            webp_quality: config.webp_quality,
+            ..FromImageOptions::default()
+        },
+    }
+}
+
+/// The options used for encoding the thumbnail at the given optimization level.
+pub fn thumbnail_options_for_level(optim_level: u8, config: &ImageConfig) -> FromImageOptions {
+    FromImageOptions {
+        optimize_png: optim_level > 0,
+        max_size: Some(config.thumbnail_size),
+        placeholder: true,
+        perceptual_hash: true,
+        webp_quality: config.webp_quality,
+    }
+}
+
+/// Decode the full image stored in an image document.
+pub async fn decode_image_doc(image_doc: &Document) -> Result<DynamicImage, String> {
+    let image_bytes = image_doc
+        .get_binary_generic("data")
+        .map_err(|e| e.to_string())?
+        .clone();
+    let content_type = image_doc
+        .get_str("content_type")
+        .map_err(|e| e.to_string())?;
+
+    // create a DynamicImage from the bytes and content type
+    let mut read_image = Reader::new(Cursor::new(image_bytes));
+
+    match util::mimetype_to_format(content_type) {
+        Some(format) => read_image.set_format(format),
+        None => {
+            read_image = read_image
+                .with_guessed_format()
+                .map_err(|e| e.to_string())?
+        }
+    }
+
+    decode_with_limits(read_image)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 /// Optimize an image from the database and bump its compression level.
+#[instrument(
+    name = "optimize",
+    skip_all,
+    fields(image_id = image_doc.get_str("_id").unwrap_or_default())
+)]
 pub async fn optimize_image_and_update(
     images_collection: &Collection<Document>,
     image_doc: &Document,
+    config: &ImageConfig,
 ) -> Result<(), String> {
     let image_id = ImageId(
         image_doc
@@ -25,58 +87,30 @@ pub async fn optimize_image_and_update(
             .expect("Image id must be a string")
             .to_string(),
     );
-    let image_bytes = image_doc
-        .get_binary_generic("data")
-        .expect("data must be set")
-        .clone();
-    let content_type = image_doc
-        .get_str("content_type")
-        .expect("content_type must be set");
     let optimization_level = image_doc
         .get_i32("optim_level")
         .expect("optim_level must be set") as u8;
 
-    // create a DynamicImage from the bytes and content type
-    let mut read_image = Reader::new(Cursor::new(image_bytes));
-
-    read_image.set_format(util::mimetype_to_format(content_type));
-
-    let image = task::spawn_blocking(|| read_image.decode())
-        .await
-        .unwrap()
-        .map_err(|e| e.to_string())?;
+    if optimization_level > 0 {
+        return Err("This image is already too compressed!".to_string());
+    }
 
-    let encoded_image_future = match optimization_level {
-        0 => from_image(
-            image.clone(),
-            FromImageOptions {
-                optimize_png: true,
-                max_size: Some(1024),
-                ..FromImageOptions::default()
-            },
-        ),
-        _ => return Err("This image is already too compressed!".to_string()),
-    };
+    let image = decode_image_doc(image_doc).await?;
 
+    let encoded_image_future = from_image(
+        image.clone(),
+        image_options_for_level(optimization_level + 1, config),
+    );
     let encoded_thumbnail_future = from_image(
         image,
-        FromImageOptions {
-            optimize_png: true,
-            max_size: Some(128),
-            ..FromImageOptions::default()
-        },
+        thumbnail_options_for_level(optimization_level + 1, config),
     );
 
     let (encoded_image_result, encoded_thumbnail_result) =
         join!(encoded_image_future, encoded_thumbnail_future);
     let (encoded_image, encoded_thumbnail) = (encoded_image_result?, encoded_thumbnail_result?);
 
-    info!(
-        "inserting into database {}, new optimization level: {}",
-        image_id,
-        optimization_level + 1
-    );
-    db::insert_image(
+    let updated = db::update_image(
         images_collection,
         &db::NewImage {
             id: &image_id,
@@ -86,56 +120,126 @@ pub async fn optimize_image_and_update(
 
             thumbnail_data: &encoded_thumbnail.data,
             thumbnail_content_type: &encoded_thumbnail.content_type,
+            placeholder: encoded_thumbnail.placeholder.as_ref(),
+            perceptual_hash: encoded_thumbnail.perceptual_hash,
 
             size: encoded_image.size,
 
             optim_level: optimization_level + 1,
         },
+        optimization_level,
     )
     .await
-    .map_err(|_| "Inserting into database failed")?;
+    .map_err(|_| "Updating the database failed")?;
+    if !updated {
+        // it was deleted, or the background scan and the upload both
+        // optimized it and the other one finished first
+        info!("image was deleted or already optimized");
+        return Ok(());
+    }
+
+    let old_size = image_doc
+        .get_binary_generic("data")
+        .map_or(0, |data| data.len());
+    info!(
+        optim_level = optimization_level + 1,
+        old_size,
+        new_size = encoded_image.data.len(),
+        "optimized image"
+    );
+    metrics::OPTIMIZATIONS.inc();
+    metrics::OPTIMIZATION_BACKLOG.dec();
+    metrics::OPTIMIZATION_BYTES_SAVED
+        .inc_by(old_size.saturating_sub(encoded_image.data.len()) as u64);
 
     Ok(())
 }
 
-/// Find images that should be optimized or deleted from the database
+/// The name of the background scan in the jobs collection, used for resuming.
+const JOB_NAME: &str = "background-optimization";
+
+/// Find images that should be optimized or deleted from the database.
+///
+/// Only the ids are fetched while scanning, the image data is loaded when an
+/// image is actually optimized. Images are optimized in batches of
+/// `images.optimize_concurrency` at a time, and the last id of every finished batch
+/// is saved so restarting the server doesn't start the scan over.
+#[instrument(name = "job", skip_all, fields(name = JOB_NAME))]
 pub async fn optimize_images_from_database(
-    images_collection: &Collection<Document>,
+    collections: &db::Collections,
+    config: &ImageConfig,
 ) -> Result<(), String> {
-    println!("optimize_images_from_database");
-    // delete images that haven't been viewed in a year
-    let target_datetime =
-        bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() - 31_536_000_000);
-    images_collection
-        .delete_many(
-            doc! {
-                "last_seen": {"$lt": target_datetime},
-            },
-            None,
-        )
+    // delete images that haven't been viewed in a while
+    db::delete_expired_images(&collections.images, config.expiry_millis())
         .await
         .map_err(|e| e.to_string())?;
 
-    // images with an optimization level of 0
-    let mut images_cursor = images_collection
-        .find(
-            doc! {
-                "optim_level": 0
-            },
-            None,
+    // counted once here instead of on every scrape of /metrics, uploads and
+    // optimizations keep it up to date after this
+    let backlog = db::count_images(&collections.images, doc! { "optim_level": 0 })
+        .await
+        .map_err(|e| e.to_string())?;
+    metrics::OPTIMIZATION_BACKLOG.set(backlog as i64);
+
+    let concurrency = config.optimize_concurrency;
+    let mut last_id = db::get_job_checkpoint(&collections.jobs, JOB_NAME)
+        .await
+        .map_err(|e| e.to_string())?;
+    if let Some(last_id) = &last_id {
+        info!(after = %last_id, "Resuming optimization");
+    }
+
+    loop {
+        // images with an optimization level of 0
+        let ids = db::get_image_ids_after(
+            &collections.images,
+            doc! { "optim_level": 0 },
+            last_id.as_ref(),
+            concurrency as i64,
         )
         .await
         .map_err(|e| e.to_string())?;
-    while let Some(im) = images_cursor.try_next().await.map_err(|e| e.to_string())? {
-        // if there's an error, just ignore it
-        optimize_image_and_update(images_collection, &im)
+        if ids.is_empty() {
+            break;
+        }
+
+        let results = join_all(
+            ids.iter()
+                .map(|id| optimize_image_by_id(&collections.images, id, config)),
+        )
+        .await;
+        for (id, result) in ids.iter().zip(results) {
+            // if there's an error, just ignore it
+            if let Err(e) = result {
+                error!(image_id = %id, error = %e, "Error optimizing image");
+            }
+        }
+
+        let batch_last_id = ids.last().unwrap().clone();
+        db::set_job_checkpoint(&collections.jobs, JOB_NAME, &batch_last_id)
             .await
-            .unwrap_or_else(|e| {
-                println!("Error optimizing image: {}", e);
-            });
-        info!("optimized image {}", im.get_str("_id").unwrap());
+            .map_err(|e| e.to_string())?;
+        last_id = Some(batch_last_id);
     }
+
+    // the scan finished, so the next one should start from the beginning
+    db::clear_job_checkpoint(&collections.jobs, JOB_NAME)
+        .await
+        .map_err(|e| e.to_string())?;
     info!("Done optimizing images.");
 
     Ok(())
 }
+
+/// Load an image's data and optimize it.
+async fn optimize_image_by_id(
+    images_collection: &Collection<Document>,
+    image_id: &ImageId,
+    config: &ImageConfig,
+) -> Result<(), String> {
+    let image_doc = db::get_image(images_collection, &image_id.0)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("Image was deleted")?;
+    optimize_image_and_update(images_collection, &image_doc, config).await
+}
@@ -0,0 +1,116 @@
+//! Keeping images that were taken down from being uploaded again.
+//!
+//! The blocklist has exact SHA-256 hashes of files and perceptual hashes (see
+//! [`similar`]), so re-uploads at a different size or in a different format
+//! are caught too.
+
+use crate::background_optimization::decode_image_doc;
+use crate::config::BlocklistConfig;
+use crate::error::UploadError;
+use crate::util::{self, ImageId};
+use crate::{db, similar};
+use bson::Document;
+use mongodb::Collection;
+
+/// The kind of a blocklist entry that's the SHA-256 hash of a file, in hex.
+pub const SHA256: &str = "sha256";
+/// The kind of a blocklist entry that's a perceptual hash, in hex.
+pub const DHASH: &str = "dhash";
+
+pub fn format_dhash(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+pub fn parse_dhash(hash: &str) -> Result<u64, String> {
+    u64::from_str_radix(hash, 16).map_err(|_| format!("{:?} isn't a 64-bit hex hash", hash))
+}
+
+/// Make sure a file with this SHA-256 hash is allowed to be uploaded.
+pub async fn check_sha256(
+    blocklist_collection: &Collection<Document>,
+    config: &BlocklistConfig,
+    sha256: &str,
+) -> Result<(), UploadError> {
+    if db::is_blocklisted(blocklist_collection, sha256).await? {
+        return Err(UploadError::UnavailableForLegalReasons(
+            config.message.clone(),
+        ));
+    }
+    Ok(())
+}
+
+/// Make sure an image with this perceptual hash is allowed to be uploaded.
+/// The blocklist is expected to be small, so this checks every entry.
+pub async fn check_perceptual_hash(
+    blocklist_collection: &Collection<Document>,
+    config: &BlocklistConfig,
+    hash: u64,
+) -> Result<(), UploadError> {
+    let entries = db::get_blocklist_entries(blocklist_collection, Some(DHASH)).await?;
+    let blocked = entries
+        .iter()
+        .filter_map(|entry| entry.get_str("_id").ok())
+        .filter_map(|blocked_hash| parse_dhash(blocked_hash).ok())
+        .any(|blocked_hash| similar::hamming_distance(blocked_hash, hash) <= config.max_distance);
+    if blocked {
+        return Err(UploadError::UnavailableForLegalReasons(
+            config.message.clone(),
+        ));
+    }
+    Ok(())
+}
+
+/// Add the hashes of an uploaded image to the blocklist, then delete it.
+/// Returns the hashes that were added.
+pub async fn block_image(
+    collections: &db::Collections,
+    image_id: &ImageId,
+    reason: Option<&str>,
+) -> Result<Vec<(&'static str, String)>, String> {
+    let image_doc = db::get_image(&collections.images, &image_id.0)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("No image found")?;
+
+    let sha256 = match image_doc.get_str("sha256") {
+        Ok(sha256) => sha256.to_string(),
+        // images from before the uploaded file's hash was saved, which only
+        // catches re-uploads of the file we stored
+        Err(_) => util::sha256_hex(
+            image_doc
+                .get_binary_generic("data")
+                .map_err(|e| e.to_string())?,
+        ),
+    };
+    let perceptual_hash = match similar::hash_from_doc(&image_doc) {
+        Some(hash) => hash,
+        // images from before perceptual hashes were added
+        None => similar::dhash(&decode_image_doc(&image_doc).await?),
+    };
+    let hashes = vec![(SHA256, sha256), (DHASH, format_dhash(perceptual_hash))];
+
+    for (kind, hash) in &hashes {
+        db::insert_blocklist_entry(&collections.blocklist, kind, hash, reason, Some(image_id))
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    db::delete_image(&collections.images, image_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dhash_hex_round_trips() {
+        for hash in [0, 1, 0xdead_beef, u64::MAX] {
+            assert_eq!(parse_dhash(&format_dhash(hash)), Ok(hash));
+        }
+        assert_eq!(format_dhash(1).len(), 16);
+        assert!(parse_dhash("not hex").is_err());
+    }
+}
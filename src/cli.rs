@@ -0,0 +1,153 @@
+//! The command line interface. Running the binary without a subcommand starts
+//! the server.
+
+use crate::similar;
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about = "An extremely simple image host.")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the web server (this is the default)
+    Serve,
+    /// Upload image files from the local filesystem
+    Import(ImportArgs),
+    /// Save the stored bytes of an image to a file
+    Export {
+        id: String,
+        /// Where to write the image, defaults to the id with the right extension
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Back up images to an archive or restore them from one
+    #[command(subcommand)]
+    Archive(ArchiveCommand),
+    /// Delete an image
+    Delete { id: String },
+    /// Show how many images there are and how much space they use
+    Stats,
+    /// Delete images that haven't been viewed in a long time
+    Gc,
+    /// Manage API keys
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Re-encode stored images with the current encoder settings
+    Reencode(ReencodeArgs),
+    /// Keep images from being uploaded
+    #[command(subcommand)]
+    Blocklist(BlocklistCommand),
+    /// List the images that look like an image, such as re-uploads of it at a
+    /// different size
+    Similar {
+        /// The id of the image to compare with
+        #[arg(required_unless_present = "file")]
+        id: Option<String>,
+        /// Compare with an image file instead of an uploaded image
+        #[arg(long, conflicts_with = "id")]
+        file: Option<PathBuf>,
+        /// The max number of bits out of 64 that the perceptual hashes can
+        /// differ by
+        #[arg(long, default_value_t = similar::DEFAULT_MAX_DISTANCE)]
+        distance: u32,
+    },
+    /// Fix the stored width and height of every image by reading them from
+    /// the image data
+    BackfillDimensions {
+        /// Ignore the progress saved by an interrupted run and start over
+        #[arg(long)]
+        restart: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ArchiveCommand {
+    /// Write every image to a tar archive
+    Export { path: PathBuf },
+    /// Add the images from a tar archive, keeping their ids. Images with ids
+    /// that are already taken are skipped.
+    Import { path: PathBuf },
+}
+
+#[derive(Subcommand)]
+pub enum BlocklistCommand {
+    /// Block an uploaded image and anything that looks like it, and delete it
+    AddImage {
+        id: String,
+        /// Why the image was blocked, only shown to admins
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Block a hash
+    Add {
+        /// The SHA-256 hash of a file, in hex
+        #[arg(long, required_unless_present = "dhash", conflicts_with = "dhash")]
+        sha256: Option<String>,
+        /// A 64-bit perceptual hash, in hex
+        #[arg(long)]
+        dhash: Option<String>,
+        /// Why the hash was blocked, only shown to admins
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Show every blocked hash
+    List,
+    /// Unblock a hash
+    Remove { hash: String },
+}
+
+#[derive(Subcommand)]
+pub enum KeysCommand {
+    /// Create a new API key and print it. The key can't be shown again later.
+    Create {
+        /// A name to remember who the key belongs to
+        #[arg(long)]
+        name: String,
+        /// Allow the key to use admin endpoints
+        #[arg(long)]
+        admin: bool,
+    },
+}
+
+#[derive(Args)]
+pub struct ImportArgs {
+    /// The image files to upload. Directories are searched for images
+    /// recursively.
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+    /// How many images to encode at the same time
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+    /// Use the file names (without the extension) as the image ids instead of
+    /// generating random ones
+    #[arg(long)]
+    pub ids_from_filenames: bool,
+    /// Write a CSV file with the url of every imported file
+    #[arg(long)]
+    pub csv: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct ReencodeArgs {
+    /// Only re-encode images matching this MongoDB filter, written as JSON
+    /// (for example '{"content_type": "image/png"}')
+    #[arg(long)]
+    pub filter: Option<String>,
+    /// How many images to re-encode at the same time
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+    /// Ignore the progress saved by an interrupted run and start over
+    #[arg(long)]
+    pub restart: bool,
+    /// Replace images even if the new encoding isn't smaller
+    #[arg(long)]
+    pub force: bool,
+    /// Only show how much would be saved, without replacing any images
+    #[arg(long)]
+    pub dry_run: bool,
+}
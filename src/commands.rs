@@ -0,0 +1,305 @@
+//! The administration subcommands, so maintenance can be scripted without
+//! writing queries against the database by hand.
+
+use crate::cli::BlocklistCommand;
+use crate::config::Config;
+use crate::similar::{self, SimilarImage};
+use crate::{blocklist, db, util};
+use bson::Document;
+use image::io::Reader;
+use image::ImageFormat;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use util::ImageId;
+
+/// Write the stored bytes of an image to a file.
+pub async fn export(
+    collections: &db::Collections,
+    id: &str,
+    output: Option<PathBuf>,
+) -> Result<(), String> {
+    let image_doc = db::get_image(&collections.images, id)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("No image found")?;
+    let data = image_doc
+        .get_binary_generic("data")
+        .map_err(|e| e.to_string())?;
+    let content_type = image_doc
+        .get_str("content_type")
+        .map_err(|e| e.to_string())?;
+
+    let output = output.unwrap_or_else(|| {
+        let extension = ImageFormat::from_mime_type(content_type)
+            .and_then(|f| f.extensions_str().first())
+            .unwrap_or(&"bin");
+        PathBuf::from(format!("{}.{}", id, extension))
+    });
+    tokio::fs::write(&output, data)
+        .await
+        .map_err(|e| e.to_string())?;
+    println!("Wrote {} bytes to {}", data.len(), output.display());
+    Ok(())
+}
+
+pub async fn delete(collections: &db::Collections, id: &str) -> Result<(), String> {
+    let deleted = db::delete_image(&collections.images, &ImageId(id.to_string()))
+        .await
+        .map_err(|e| e.to_string())?;
+    if !deleted {
+        return Err("No image found".to_string());
+    }
+    println!("Deleted {}", id);
+    Ok(())
+}
+
+/// Print a table of how much space images use, by content type and
+/// optimization level.
+pub async fn stats(collections: &db::Collections) -> Result<(), String> {
+    let stats = db::get_storage_stats(&collections.images)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    println!(
+        "{:<16} {:>11} {:>10} {:>14} {:>14}",
+        "content type", "optim level", "images", "bytes", "thumb bytes"
+    );
+    for row in &stats {
+        println!(
+            "{:<16} {:>11} {:>10} {:>14} {:>14}",
+            row.content_type, row.optim_level, row.count, row.bytes, row.thumbnail_bytes
+        );
+    }
+    println!(
+        "{:<16} {:>11} {:>10} {:>14} {:>14}",
+        "total",
+        "",
+        stats.iter().map(|r| r.count).sum::<i64>(),
+        stats.iter().map(|r| r.bytes).sum::<i64>(),
+        stats.iter().map(|r| r.thumbnail_bytes).sum::<i64>()
+    );
+    Ok(())
+}
+
+/// Delete images that have expired.
+pub async fn gc(collections: &db::Collections, config: &Config) -> Result<(), String> {
+    let deleted = db::delete_expired_images(&collections.images, config.images.expiry_millis())
+        .await
+        .map_err(|e| e.to_string())?;
+    println!("Deleted {} expired images", deleted);
+    Ok(())
+}
+
+/// Create an API key and print it.
+pub async fn create_key(
+    collections: &db::Collections,
+    name: &str,
+    admin: bool,
+) -> Result<(), String> {
+    let key = util::generate_api_key();
+    db::insert_api_key(
+        &collections.keys,
+        &util::sha256_hex(key.as_bytes()),
+        name,
+        admin,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    println!("{}", key);
+    Ok(())
+}
+
+/// Print the images that look like the given image or file.
+pub async fn similar(
+    collections: &db::Collections,
+    config: &Config,
+    id: Option<&str>,
+    file: Option<&Path>,
+    max_distance: u32,
+) -> Result<(), String> {
+    similar::load_index(&collections.images).await?;
+    let found = match (id, file) {
+        (_, Some(file)) => {
+            let hash = similar::hash_file(file, &config.images)
+                .await
+                .map_err(|e| e.to_string())?;
+            similar::INDEX.read().unwrap().search(hash, max_distance)
+        }
+        (Some(id), None) => similar::similar_to_id(&collections.images, id, max_distance)
+            .await?
+            .ok_or("No image with a perceptual hash found")?,
+        (None, None) => return Err("Either an id or a file is required".to_string()),
+    };
+
+    for image in found
+        .into_iter()
+        .map(|found| SimilarImage::new(found, &config.host))
+    {
+        println!("{:>2} {}", image.distance, image.url);
+    }
+    Ok(())
+}
+
+pub async fn blocklist(
+    collections: &db::Collections,
+    command: BlocklistCommand,
+) -> Result<(), String> {
+    match command {
+        BlocklistCommand::AddImage { id, reason } => {
+            let hashes =
+                blocklist::block_image(collections, &ImageId(id.clone()), reason.as_deref())
+                    .await?;
+            for (kind, hash) in hashes {
+                println!("Blocked {} {}", kind, hash);
+            }
+            println!("Deleted {}", id);
+        }
+        BlocklistCommand::Add {
+            sha256,
+            dhash,
+            reason,
+        } => {
+            let (kind, hash) = match (sha256, dhash) {
+                (Some(sha256), _) => {
+                    let sha256 = sha256.to_lowercase();
+                    if sha256.len() != 64 || hex::decode(&sha256).is_err() {
+                        return Err(format!("{:?} isn't a SHA-256 hash", sha256));
+                    }
+                    (blocklist::SHA256, sha256)
+                }
+                (None, Some(dhash)) => (
+                    blocklist::DHASH,
+                    blocklist::format_dhash(blocklist::parse_dhash(&dhash)?),
+                ),
+                (None, None) => return Err("Either --sha256 or --dhash is required".to_string()),
+            };
+            db::insert_blocklist_entry(
+                &collections.blocklist,
+                kind,
+                &hash,
+                reason.as_deref(),
+                None,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+            println!("Blocked {} {}", kind, hash);
+        }
+        BlocklistCommand::List => {
+            let entries = db::get_blocklist_entries(&collections.blocklist, None)
+                .await
+                .map_err(|e| e.to_string())?;
+            for entry in entries {
+                println!(
+                    "{:<6} {:<64} {:<8} {}",
+                    entry.get_str("kind").unwrap_or_default(),
+                    entry.get_str("_id").unwrap_or_default(),
+                    entry.get_str("source_image").unwrap_or_default(),
+                    entry.get_str("reason").unwrap_or_default()
+                );
+            }
+        }
+        BlocklistCommand::Remove { hash } => {
+            let removed = db::delete_blocklist_entry(&collections.blocklist, &hash.to_lowercase())
+                .await
+                .map_err(|e| e.to_string())?;
+            if !removed {
+                return Err("That hash isn't blocked".to_string());
+            }
+            println!("Unblocked {}", hash);
+        }
+    }
+    Ok(())
+}
+
+/// The name of the dimension backfill in the jobs collection, used for
+/// resuming.
+const BACKFILL_DIMENSIONS_JOB: &str = "backfill-dimensions";
+
+/// Read the width and height of every stored image from its data and fix the
+/// ones that were saved wrong. Only the image headers are read, so this is
+/// much faster than decoding everything.
+pub async fn backfill_dimensions(
+    collections: &db::Collections,
+    restart: bool,
+) -> Result<(), String> {
+    let mut last_id = if restart {
+        db::clear_job_checkpoint(&collections.jobs, BACKFILL_DIMENSIONS_JOB)
+            .await
+            .map_err(|e| e.to_string())?;
+        None
+    } else {
+        db::get_job_checkpoint(&collections.jobs, BACKFILL_DIMENSIONS_JOB)
+            .await
+            .map_err(|e| e.to_string())?
+    };
+    if let Some(last_id) = &last_id {
+        println!("Resuming after {}", last_id);
+    }
+
+    let (mut scanned, mut fixed, mut failed) = (0, 0, 0);
+    loop {
+        let ids =
+            db::get_image_ids_after(&collections.images, Document::new(), last_id.as_ref(), 100)
+                .await
+                .map_err(|e| e.to_string())?;
+        if ids.is_empty() {
+            break;
+        }
+
+        for id in &ids {
+            scanned += 1;
+            let result = async {
+                let image_doc = db::get_image(&collections.images, &id.0)
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .ok_or("Image was deleted")?;
+                let size = stored_image_dimensions(&image_doc)?;
+                db::set_image_dimensions(&collections.images, id, size)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            .await;
+            match result {
+                Ok(true) => fixed += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    println!("{}: error reading dimensions: {}", id, e);
+                    failed += 1;
+                }
+            }
+        }
+
+        let batch_last_id = ids.last().unwrap().clone();
+        db::set_job_checkpoint(&collections.jobs, BACKFILL_DIMENSIONS_JOB, &batch_last_id)
+            .await
+            .map_err(|e| e.to_string())?;
+        last_id = Some(batch_last_id);
+    }
+
+    db::clear_job_checkpoint(&collections.jobs, BACKFILL_DIMENSIONS_JOB)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    println!(
+        "Checked {} images, fixed {}, {} failed",
+        scanned, fixed, failed
+    );
+    Ok(())
+}
+
+/// Read the dimensions from the header of an image document's data.
+fn stored_image_dimensions(image_doc: &Document) -> Result<(u32, u32), String> {
+    let data = image_doc
+        .get_binary_generic("data")
+        .map_err(|e| e.to_string())?;
+    let content_type = image_doc
+        .get_str("content_type")
+        .map_err(|e| e.to_string())?;
+
+    let mut reader = Reader::new(Cursor::new(data));
+    match util::mimetype_to_format(content_type) {
+        Some(format) => reader.set_format(format),
+        None => reader = reader.with_guessed_format().map_err(|e| e.to_string())?,
+    }
+    reader.into_dimensions().map_err(|e| e.to_string())
+}
@@ -0,0 +1,527 @@
+//! The settings for a deployment, read from `Rocket.toml` next to Rocket's own
+//! settings (like the upload `limits`), in the table for the current profile.
+//! Anything can be overridden with environment variables: `ROCKET_HOST` or
+//! `IMAGE_HOST_HOST` for top-level settings, and `IMAGE_HOST_IMAGES__WEBP_QUALITY`
+//! for nested ones (like `IMAGE_HOST_SIGNING__SECRET`, which is better kept out
+//! of the config file). The environment variables from before there was a
+//! config file, like `MONGODB_URI` and `TUS_DIR`, still work too (see
+//! [`LEGACY_ENV`]).
+//!
+//! The config is loaded and checked once at startup, and routes get it as
+//! managed state.
+
+use crate::logging::LogFormat;
+use crate::util;
+use crate::visibility::MAX_URL_TTL_HOURS;
+use ipnet::IpNet;
+use rocket::figment::providers::Env;
+use rocket::figment::Figment;
+use rocket::serde::{Deserialize, Deserializer, Serialize};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+/// The environment variables used before there was a config file, and the
+/// settings they're for now.
+const LEGACY_ENV: &[(&str, &str)] = &[
+    ("HOST", "host"),
+    ("MONGODB_URI", "database.uri"),
+    ("MONGODB_DB_NAME", "database.name"),
+    ("MAX_IMAGE_WIDTH", "decoding.max_width"),
+    ("MAX_IMAGE_HEIGHT", "decoding.max_height"),
+    ("MAX_DECODE_ALLOC", "decoding.max_alloc"),
+    ("MAX_CONCURRENT_DECODES", "decoding.max_concurrent"),
+    ("OPTIMIZE_CONCURRENCY", "images.optimize_concurrency"),
+    ("URL_UPLOAD_ALLOWLIST", "url_upload.allowlist"),
+    ("URL_UPLOAD_MAX_BYTES", "url_upload.max_bytes"),
+    ("URL_UPLOAD_TIMEOUT_SECS", "url_upload.timeout_secs"),
+    ("TUS_DIR", "tus.dir"),
+    ("TUS_MAX_SIZE", "tus.max_size"),
+    ("TUS_EXPIRY_SECS", "tus.expiry_secs"),
+    ("BLOCKLIST_MESSAGE", "blocklist.message"),
+    ("BLOCKLIST_MAX_DISTANCE", "blocklist.max_distance"),
+    ("MIN_FREE_DISK_BYTES", "health.min_free_disk_bytes"),
+    ("LOG_FORMAT", "log.format"),
+];
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Config {
+    /// The domain the site is on, for making links to images
+    pub host: String,
+    pub database: DatabaseConfig,
+    pub ids: IdConfig,
+    pub images: ImageConfig,
+    pub signing: SigningConfig,
+    pub decoding: DecodingConfig,
+    pub url_upload: UrlUploadConfig,
+    pub tus: TusConfig,
+    pub blocklist: BlocklistConfig,
+    pub health: HealthConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct DatabaseConfig {
+    /// The MongoDB connection string
+    pub uri: String,
+    /// The name of the database to keep everything in
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct IdConfig {
+    /// How many characters the ids of new images have
+    pub length: usize,
+    /// The characters random ids are made of
+    pub alphabet: String,
+    /// How many times in a row an upload can pick an id that's taken before
+    /// ids get a character longer
+    pub max_collisions: u32,
+    /// How many characters the ids of unlisted and private images have, so
+    /// they can't be guessed
+    pub unlisted_length: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ImageConfig {
+    /// The max width and height of thumbnails
+    pub thumbnail_size: u32,
+    /// The max width and height of images after they're optimized in the
+    /// background
+    pub optimized_max_size: u32,
+    /// From 0 to 100
+    pub webp_quality: f32,
+    /// Images that haven't been viewed in this many days are deleted
+    pub expiry_days: u32,
+    /// How many images are optimized at the same time in the background
+    pub optimize_concurrency: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct SigningConfig {
+    /// The key for signing urls to private images. Private images can't be
+    /// uploaded if this isn't set.
+    pub secret: String,
+    /// How long signed urls work for if the API key making them doesn't say
+    pub url_ttl_hours: u64,
+}
+
+/// Limits on the images we're willing to decode, so a tiny file that claims to
+/// be enormous can't use up all of our memory.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct DecodingConfig {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// The most bytes a decoded image can take up
+    pub max_alloc: u64,
+    /// Decoding uses a lot of memory, so only this many images are decoded at
+    /// the same time
+    pub max_concurrent: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct UrlUploadConfig {
+    /// Private networks (like `10.0.0.0/8`) that images can be downloaded
+    /// from anyway
+    #[serde(deserialize_with = "comma_separated")]
+    pub allowlist: Vec<String>,
+    /// The biggest image that can be downloaded
+    pub max_bytes: u64,
+    pub timeout_secs: u64,
+}
+
+/// Resumable uploads.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct TusConfig {
+    /// Where uploads are kept until they're finished
+    pub dir: PathBuf,
+    /// The biggest upload that can be started
+    pub max_size: u64,
+    /// How long an upload can go without receiving data before it's deleted
+    pub expiry_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct BlocklistConfig {
+    /// The message sent to people uploading a blocked image
+    pub message: String,
+    /// How many bits a perceptual hash can differ by from one on the blocklist
+    /// and still be blocked
+    pub max_distance: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct HealthConfig {
+    /// The server isn't ready if a directory uploads are written to has less
+    /// free space than this
+    pub min_free_disk_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+}
+
+/// Lists can also be one comma separated string, which is how
+/// `URL_UPLOAD_ALLOWLIST` was written before there was a config file.
+fn comma_separated<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(crate = "rocket::serde", untagged)]
+    enum List {
+        Many(Vec<String>),
+        One(String),
+    }
+    Ok(match List::deserialize(deserializer)? {
+        List::Many(items) => items,
+        List::One(items) => items
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+    })
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "i.matdoes.dev".to_string(),
+            database: DatabaseConfig::default(),
+            ids: IdConfig::default(),
+            images: ImageConfig::default(),
+            signing: SigningConfig::default(),
+            decoding: DecodingConfig::default(),
+            url_upload: UrlUploadConfig::default(),
+            tus: TusConfig::default(),
+            blocklist: BlocklistConfig::default(),
+            health: HealthConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for IdConfig {
+    fn default() -> Self {
+        IdConfig {
+            length: 5,
+            alphabet: String::from_utf8(util::ID_CHARSET.to_vec()).unwrap(),
+            max_collisions: 3,
+            unlisted_length: 24,
+        }
+    }
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        ImageConfig {
+            thumbnail_size: 128,
+            optimized_max_size: 1024,
+            webp_quality: 90.0,
+            expiry_days: 365,
+            optimize_concurrency: 4,
+        }
+    }
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        SigningConfig {
+            secret: String::new(),
+            url_ttl_hours: 24 * 7,
+        }
+    }
+}
+
+impl Default for DecodingConfig {
+    fn default() -> Self {
+        DecodingConfig {
+            max_width: 16384,
+            max_height: 16384,
+            max_alloc: 512 * 1024 * 1024,
+            max_concurrent: std::thread::available_parallelism().map_or(4, |n| n.get()),
+        }
+    }
+}
+
+impl Default for UrlUploadConfig {
+    fn default() -> Self {
+        UrlUploadConfig {
+            allowlist: Vec::new(),
+            max_bytes: 16 * 1024 * 1024,
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for TusConfig {
+    fn default() -> Self {
+        TusConfig {
+            dir: std::env::temp_dir().join("image-host-tus"),
+            max_size: 100 * 1024 * 1024,
+            expiry_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        BlocklistConfig {
+            message: "This image can't be uploaded here.".to_string(),
+            max_distance: 6,
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            min_free_disk_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl ImageConfig {
+    /// How long an image can go without being viewed before it gets deleted.
+    pub fn expiry_millis(&self) -> i64 {
+        self.expiry_days as i64 * 24 * 60 * 60 * 1000
+    }
+}
+
+impl UrlUploadConfig {
+    /// The allowlist, parsed. Single addresses are allowed too.
+    pub fn allowed_networks(&self) -> Result<Vec<IpNet>, String> {
+        self.allowlist
+            .iter()
+            .map(|net| {
+                net.parse::<IpNet>()
+                    .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("url_upload.allowlist has an invalid network: {}", net))
+            })
+            .collect()
+    }
+}
+
+impl Config {
+    /// Make sure the settings make sense, so a typo is caught at startup
+    /// instead of when the first image is uploaded.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.host.is_empty() {
+            return Err("host must be set".to_string());
+        }
+        if self.database.uri.is_empty() {
+            return Err("database.uri (or MONGODB_URI) must be set".to_string());
+        }
+        if self.database.name.is_empty() {
+            return Err("database.name (or MONGODB_DB_NAME) must be set".to_string());
+        }
+        if !(3..=64).contains(&self.ids.length) {
+            return Err(format!(
+                "ids.length must be from 3 to 64, not {}",
+                self.ids.length
+            ));
+        }
+        if !self.ids.alphabet.bytes().all(util::is_id_char) {
+            return Err(
+                "ids.alphabet can only have letters, numbers, dashes and underscores".to_string(),
+            );
+        }
+        let mut alphabet = self.ids.alphabet.clone().into_bytes();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+        if alphabet.len() != self.ids.alphabet.len() || alphabet.len() < 2 {
+            return Err("ids.alphabet needs at least 2 characters, with no repeats".to_string());
+        }
+        if self.ids.max_collisions == 0 {
+            return Err("ids.max_collisions can't be 0".to_string());
+        }
+        if !(16..=64).contains(&self.ids.unlisted_length) {
+            return Err(format!(
+                "ids.unlisted_length must be from 16 to 64, not {}",
+                self.ids.unlisted_length
+            ));
+        }
+        if self.images.thumbnail_size == 0 {
+            return Err("images.thumbnail_size can't be 0".to_string());
+        }
+        if self.images.optimized_max_size < self.images.thumbnail_size {
+            return Err(
+                "images.optimized_max_size can't be smaller than images.thumbnail_size".to_string(),
+            );
+        }
+        if !(0.0..=100.0).contains(&self.images.webp_quality) {
+            return Err(format!(
+                "images.webp_quality must be from 0 to 100, not {}",
+                self.images.webp_quality
+            ));
+        }
+        if self.images.expiry_days == 0 {
+            return Err("images.expiry_days can't be 0".to_string());
+        }
+        if !self.signing.secret.is_empty() && self.signing.secret.len() < 32 {
+            return Err("signing.secret must be at least 32 characters".to_string());
+        }
+        if !(1..=MAX_URL_TTL_HOURS).contains(&self.signing.url_ttl_hours) {
+            return Err(format!(
+                "signing.url_ttl_hours must be from 1 to {}, not {}",
+                MAX_URL_TTL_HOURS, self.signing.url_ttl_hours
+            ));
+        }
+        if self.images.optimize_concurrency == 0 {
+            return Err("images.optimize_concurrency can't be 0".to_string());
+        }
+        if self.decoding.max_width == 0 || self.decoding.max_height == 0 {
+            return Err("decoding.max_width and decoding.max_height can't be 0".to_string());
+        }
+        if self.decoding.max_alloc == 0 {
+            return Err("decoding.max_alloc can't be 0".to_string());
+        }
+        if self.decoding.max_concurrent == 0 {
+            return Err("decoding.max_concurrent can't be 0".to_string());
+        }
+        self.url_upload.allowed_networks()?;
+        if self.url_upload.max_bytes == 0 {
+            return Err("url_upload.max_bytes can't be 0".to_string());
+        }
+        if self.url_upload.timeout_secs == 0 {
+            return Err("url_upload.timeout_secs can't be 0".to_string());
+        }
+        if self.tus.dir.as_os_str().is_empty() {
+            return Err("tus.dir must be set".to_string());
+        }
+        if self.tus.max_size == 0 {
+            return Err("tus.max_size can't be 0".to_string());
+        }
+        if self.tus.expiry_secs == 0 {
+            return Err("tus.expiry_secs can't be 0".to_string());
+        }
+        if self.blocklist.max_distance > 64 {
+            return Err(format!(
+                "blocklist.max_distance must be from 0 to 64, not {}",
+                self.blocklist.max_distance
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Rocket's figment with our environment variables added.
+pub fn figment() -> Figment {
+    rocket::Config::figment()
+        .merge(
+            Env::raw()
+                .only(&LEGACY_ENV.iter().map(|(name, _)| *name).collect::<Vec<_>>())
+                .map(|key| {
+                    LEGACY_ENV
+                        .iter()
+                        .find(|(name, _)| key == *name)
+                        .map_or(key.into(), |(_, setting)| (*setting).into())
+                })
+                .global(),
+        )
+        .merge(Env::prefixed("IMAGE_HOST_").split("__").global())
+}
+
+/// Read and check the config from a figment.
+pub fn from_figment(figment: &Figment) -> Result<Config, String> {
+    let config: Config = figment
+        .extract()
+        .map_err(|e| format!("Invalid config: {}", e))?;
+    config
+        .validate()
+        .map_err(|e| format!("Invalid config: {}", e))?;
+    Ok(config)
+}
+
+/// Read and check the config from `Rocket.toml` and the environment.
+pub fn load() -> Result<Config, String> {
+    from_figment(&figment())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::figment::providers::{Format, Serialized, Toml};
+
+    fn figment_from_toml(toml: &str) -> Figment {
+        Figment::from(Serialized::defaults(Config::default())).merge(Toml::string(toml))
+    }
+
+    #[test]
+    fn settings_that_arent_given_have_defaults() {
+        let config = from_figment(&figment_from_toml(
+            r#"
+            host = "img.example.com"
+            database = { uri = "mongodb://localhost", name = "images" }
+            [images]
+            webp_quality = 75
+            "#,
+        ))
+        .unwrap();
+        assert_eq!(config.host, "img.example.com");
+        assert_eq!(config.database.name, "images");
+        assert_eq!(config.images.webp_quality, 75.0);
+        assert_eq!(config.images.thumbnail_size, 128);
+        assert_eq!(config.ids.length, 5);
+        assert_eq!(config.images.expiry_millis(), 31_536_000_000);
+    }
+
+    #[test]
+    fn allowlists_can_be_comma_separated() {
+        let database = r#"database = { uri = "mongodb://localhost", name = "images" }"#;
+        for allowlist in [
+            r#"allowlist = "10.0.0.0/8, 192.168.1.1""#,
+            r#"allowlist = ["10.0.0.0/8", "192.168.1.1"]"#,
+        ] {
+            let toml = format!("{}\n[url_upload]\n{}", database, allowlist);
+            let config = from_figment(&figment_from_toml(&toml)).unwrap();
+            assert_eq!(
+                config.url_upload.allowed_networks().unwrap(),
+                vec![
+                    "10.0.0.0/8".parse::<IpNet>().unwrap(),
+                    "192.168.1.1/32".parse().unwrap()
+                ],
+                "{}",
+                allowlist
+            );
+        }
+    }
+
+    #[test]
+    fn bad_settings_are_rejected() {
+        let database = r#"database = { uri = "mongodb://localhost", name = "images" }"#;
+        assert!(from_figment(&figment_from_toml("")).is_err());
+        assert!(from_figment(&figment_from_toml(database)).is_ok());
+        for bad in [
+            "ids = { length = 1 }",
+            "ids = { alphabet = \"a\" }",
+            "ids = { alphabet = \"abca\" }",
+            "ids = { alphabet = \"ab/\" }",
+            "ids = { unlisted_length = 8 }",
+            "signing = { secret = \"hunter2\" }",
+            "images = { webp_quality = 101 }",
+            "images = { thumbnail_size = 2048 }",
+            "images = { expiry_days = \"forever\" }",
+            "images = { optimize_concurrency = 0 }",
+            "decoding = { max_concurrent = 0 }",
+            "decoding = { max_width = -1 }",
+            "url_upload = { allowlist = [\"10.0.0.0/33\"] }",
+            "url_upload = { timeout_secs = \"soon\" }",
+            "tus = { max_size = 0 }",
+            "blocklist = { max_distance = 65 }",
+            "log = { format = \"xml\" }",
+        ] {
+            let toml = format!("{}\n{}", database, bad);
+            assert!(from_figment(&figment_from_toml(&toml)).is_err(), "{}", bad);
+        }
+    }
+}
@@ -1,20 +1,52 @@
 //! Handles all the database operations.
 
-use crate::util;
+use crate::config::{DatabaseConfig, IdConfig};
+use crate::encoding::Placeholder;
+use crate::visibility::Visibility;
+use crate::{ids, metrics, similar, util};
 
 use bson::spec::BinarySubtype;
-use log::info;
+use futures::stream::TryStreamExt;
 use mongodb::{
-    bson::{doc, Document},
-    options::{ClientOptions, FindOneAndUpdateOptions, ResolverConfig, ReturnDocument},
+    bson::{doc, Bson, Document},
+    error::{ErrorKind, WriteError, WriteFailure},
+    options::{
+        ClientOptions, FindOneAndDeleteOptions, FindOneOptions, FindOptions, ResolverConfig,
+        UpdateOptions,
+    },
     results::UpdateResult,
     Client, Collection,
 };
-use std::env;
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::info;
 use util::ImageId;
 
+#[derive(Clone)]
 pub struct Collections {
     pub images: Collection<Document>,
+    /// Progress of long running jobs, so they can be resumed after being interrupted.
+    pub jobs: Collection<Document>,
+    /// Hashes of the API keys that can use authenticated endpoints.
+    pub keys: Collection<Document>,
+    /// Groups of images that were uploaded together.
+    pub albums: Collection<Document>,
+    /// Hashes of images that aren't allowed to be uploaded.
+    pub blocklist: Collection<Document>,
+    /// Images that viewers reported, for moderators to look at.
+    pub reports: Collection<Document>,
+    /// Every action moderators took.
+    pub audit_log: Collection<Document>,
+}
+
+/// How much space the images with one content type and optimization level use.
+#[derive(Serialize)]
+pub struct StorageStats {
+    pub content_type: String,
+    pub optim_level: u8,
+    pub count: i64,
+    pub bytes: i64,
+    pub thumbnail_bytes: i64,
 }
 
 pub struct NewImage<'a> {
@@ -30,112 +62,173 @@ pub struct NewImage<'a> {
 
     pub thumbnail_data: &'a Vec<u8>,
     pub thumbnail_content_type: &'a str,
+    /// Made from the thumbnail, this is left unchanged if it's None
+    pub placeholder: Option<&'a Placeholder>,
+    /// Made from the thumbnail, this is left unchanged if it's None
+    pub perceptual_hash: Option<u64>,
 }
 
-/// Check if the image with the given id exists
-pub async fn check_image_exists(
-    images_collection: &Collection<Document>,
-    id: ImageId,
-) -> Result<bool, mongodb::error::Error> {
-    let filter = doc! {"_id": id};
-    let counted_documents = images_collection
-        .count_documents(
-            filter,
-            Some(mongodb::options::CountOptions::builder().limit(1).build()),
-        )
-        .await?;
-    Ok(counted_documents > 0)
+impl NewImage<'_> {
+    /// The fields that are replaced in the image's document when it's
+    /// inserted or re-encoded.
+    fn to_set_document(&self) -> Document {
+        let mut set = doc! {
+            "data": bson::Binary { subtype: BinarySubtype::Generic, bytes: self.data.to_vec() },
+            "content_type": self.content_type,
+
+            "width": self.size.0,
+            "height": self.size.1,
+
+            "thumbnail_data": bson::Binary { subtype: BinarySubtype::Generic, bytes: self.thumbnail_data.to_vec() },
+            "thumbnail_content_type": self.thumbnail_content_type,
+
+            "optim_level": self.optim_level as i32
+        };
+        if let Some(placeholder) = self.placeholder {
+            set.insert("blurhash", &placeholder.blurhash);
+            set.insert("average_color", &placeholder.average_color);
+        }
+        if let Some(hash) = self.perceptual_hash {
+            // bson doesn't have unsigned integers
+            set.insert("dhash", hash as i64);
+        }
+        set
+    }
 }
 
 /// Connect to the MongoDB database
-pub async fn connect() -> Result<mongodb::Collection<bson::Document>, String> {
-    // read the mongodb_uri env variable
-    let mongodb_uri = match env::var("MONGODB_URI") {
-        Ok(val) => val,
-        Err(_) => return Err("MONGODB_URI must be set".to_string()),
-    };
-    // read the mongodb_db_name env variable
-    let mongodb_db_name = match env::var("MONGODB_DB_NAME") {
-        Ok(val) => val,
-        Err(_) => return Err("MONGODB_DB_NAME must be set".to_string()),
-    };
-
-    info!("Parsing mongodb uri: {}", mongodb_uri);
+pub async fn connect(config: &DatabaseConfig) -> Result<Collections, String> {
     // create the client options, we specify cloudflare because otherwise it takes forever to resolve a dns thing on windows
     // https://github.com/mongodb/mongo-rust-driver#windows-dns-note
-    let client_options =
-        match ClientOptions::parse_with_resolver_config(mongodb_uri, ResolverConfig::cloudflare())
+    let mut client_options =
+        match ClientOptions::parse_with_resolver_config(&config.uri, ResolverConfig::cloudflare())
             .await
         {
             Ok(val) => val,
             Err(err) => return Err(err.to_string()),
         };
+    // time every query for the metrics
+    client_options.command_event_handler = Some(Arc::new(metrics::DatabaseMetrics));
 
     let client = match Client::with_options(client_options) {
         Ok(val) => val,
         Err(err) => return Err(err.to_string()),
     };
-    let db = client.database(&mongodb_db_name);
-    let images_collection = db.collection::<Document>("images");
+    let db = client.database(&config.name);
+    let collections = Collections {
+        images: db.collection::<Document>("images"),
+        jobs: db.collection::<Document>("jobs"),
+        keys: db.collection::<Document>("keys"),
+        albums: db.collection::<Document>("albums"),
+        blocklist: db.collection::<Document>("blocklist"),
+        reports: db.collection::<Document>("reports"),
+        audit_log: db.collection::<Document>("audit_log"),
+    };
 
     info!("Pinging database");
-    match client
-        .database("admin")
-        .run_command(doc! {"ping": 1}, None)
-        .await
-    {
+    match ping(&collections.images).await {
         Ok(val) => val,
         Err(err) => return Err(err.to_string()),
     };
 
-    Ok(images_collection)
+    Ok(collections)
 }
 
-/// Generate a random non-duplicate image id
-pub async fn generate_image_id(
-    images_collection: &Collection<Document>,
-) -> Result<ImageId, mongodb::error::Error> {
-    info!("generating image id");
-    let mut id = util::generate_random_id(5);
-    while check_image_exists(images_collection, id.clone()).await? {
-        id = util::generate_random_id(5);
-    }
-    info!("generated image id");
-    Ok(id)
+/// Check that the database is reachable.
+pub async fn ping(images_collection: &Collection<Document>) -> Result<(), mongodb::error::Error> {
+    images_collection
+        .client()
+        .database("admin")
+        .run_command(doc! {"ping": 1}, None)
+        .await?;
+    Ok(())
 }
 
-/// Insert or update the content of an image
-pub async fn insert_image(
+/// Replace the content of an image that's still at `optim_level`, like after
+/// it's been optimized. Nothing is written if the image was deleted or changed
+/// in the meantime, so this can't bring back a deleted image. Returns whether
+/// the image was updated.
+pub async fn update_image(
     images_collection: &Collection<Document>,
     image: &NewImage<'_>,
-) -> Result<Option<bson::Document>, mongodb::error::Error> {
-    info!("inserting doc");
-    images_collection
-        .find_one_and_update(
+    optim_level: u8,
+) -> Result<bool, mongodb::error::Error> {
+    let result = images_collection
+        .update_one(
             doc! {
                 "_id": image.id,
+                "optim_level": optim_level as i32,
             },
             doc! {
-                "$setOnInsert": {
-                    "date": bson::DateTime::now(),                    
-                    "last_seen": bson::DateTime::now(),
-                },
-                "$set": {
-                    "data": bson::Binary { subtype: BinarySubtype::Generic, bytes: image.data.to_vec() },
-                    "content_type": image.content_type,
+                "$set": image.to_set_document(),
+            },
+            None,
+        )
+        .await?;
+    let updated = result.matched_count == 1;
+    if let (true, Some(hash)) = (updated, image.perceptual_hash) {
+        similar::INDEX.write().unwrap().insert(&image.id.0, hash);
+    }
+    Ok(updated)
+}
 
-                    "width": image.size.0,
-                    "height": image.size.0,
+/// Insert a newly uploaded image and return its document. If the id is
+/// already taken this fails with an error that [`is_duplicate_key_error`]
+/// matches, so two uploads can't end up with the same id. `original_sha256`
+/// is the hash of the file as it was uploaded, since the stored data is
+/// re-encoded and wouldn't match it. `api_key_id` is the id of the API key
+/// that uploaded it, if there was one.
+pub async fn insert_new_image(
+    images_collection: &Collection<Document>,
+    image: &NewImage<'_>,
+    visibility: Visibility,
+    original_sha256: &str,
+    api_key_id: Option<&str>,
+) -> Result<Document, mongodb::error::Error> {
+    let mut image_doc = doc! {
+        "_id": image.id,
+        "date": bson::DateTime::now(),
+        "last_seen": bson::DateTime::now(),
+        "visibility": visibility.as_str(),
+        "sha256": original_sha256,
+    };
+    if let Some(api_key_id) = api_key_id {
+        image_doc.insert("api_key_id", api_key_id);
+    }
+    image_doc.extend(image.to_set_document());
+    images_collection.insert_one(&image_doc, None).await?;
+    if let Some(hash) = image.perceptual_hash {
+        similar::INDEX.write().unwrap().insert(&image.id.0, hash);
+    }
+    Ok(image_doc)
+}
 
-                    "thumbnail_data": bson::Binary { subtype: BinarySubtype::Generic, bytes: image.thumbnail_data.to_vec() },
-                    "thumbnail_content_type": image.thumbnail_content_type,
+/// Insert a complete image document, like one from an archive. Returns false
+/// without changing anything if an image with the same id already exists.
+pub async fn insert_archived_image(
+    images_collection: &Collection<Document>,
+    image_doc: &Document,
+) -> Result<bool, mongodb::error::Error> {
+    match images_collection.insert_one(image_doc, None).await {
+        Ok(_) => {
+            if let (Ok(id), Some(hash)) =
+                (image_doc.get_str("_id"), similar::hash_from_doc(image_doc))
+            {
+                similar::INDEX.write().unwrap().insert(id, hash);
+            }
+            Ok(true)
+        }
+        Err(e) if is_duplicate_key_error(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
 
-                    "optim_level": image.optim_level as i32
-                }
-            },
-            FindOneAndUpdateOptions ::builder().upsert(true).return_document(ReturnDocument ::After).build()
-        )
-        .await
+/// Whether the error is from inserting a document with an id that's already taken
+pub fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    matches!(
+        *error.kind,
+        ErrorKind::Write(WriteFailure::WriteError(WriteError { code: 11000, .. }))
+    )
 }
 
 /// Bump the "last_seen" value on an image to now
@@ -165,3 +258,694 @@ pub async fn get_image(
     let filter = doc! {"_id": id};
     images_collection.find_one(filter, None).await
 }
+
+/// Get an image's document without the image or thumbnail data, for when only
+/// its metadata is needed.
+pub async fn get_image_metadata(
+    images_collection: &Collection<Document>,
+    id: &str,
+) -> Result<Option<Document>, mongodb::error::Error> {
+    images_collection
+        .find_one(
+            doc! {"_id": id},
+            FindOneOptions::builder()
+                .projection(doc! {"data": 0, "thumbnail_data": 0})
+                .build(),
+        )
+        .await
+}
+
+/// Get the ids of the images matching the filter that come after `after`, in
+/// order. This is used for walking through the whole collection in pages.
+pub async fn get_image_ids_after(
+    images_collection: &Collection<Document>,
+    filter: Document,
+    after: Option<&ImageId>,
+    limit: i64,
+) -> Result<Vec<ImageId>, mongodb::error::Error> {
+    let filter = match after {
+        Some(after) => doc! {"$and": [filter, {"_id": {"$gt": after.to_string()}}]},
+        None => filter,
+    };
+    let options = FindOptions::builder()
+        .projection(doc! {"_id": 1})
+        .sort(doc! {"_id": 1})
+        .limit(limit)
+        .build();
+    let docs: Vec<Document> = images_collection
+        .find(filter, options)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(docs
+        .into_iter()
+        .filter_map(|d| d.get("_id").cloned())
+        .filter_map(|id| ImageId::try_from(id).ok())
+        .collect())
+}
+
+/// Overwrite the stored width and height of an image. Returns whether they
+/// were different from before.
+pub async fn set_image_dimensions(
+    images_collection: &Collection<Document>,
+    image_id: &ImageId,
+    (width, height): (u32, u32),
+) -> Result<bool, mongodb::error::Error> {
+    let result = images_collection
+        .update_one(
+            doc! {"_id": image_id.to_string()},
+            doc! {"$set": {"width": width, "height": height}},
+            None,
+        )
+        .await?;
+    Ok(result.modified_count > 0)
+}
+
+/// Get the last image id that a job finished processing
+pub async fn get_job_checkpoint(
+    jobs_collection: &Collection<Document>,
+    job: &str,
+) -> Result<Option<ImageId>, mongodb::error::Error> {
+    let job_doc = jobs_collection.find_one(doc! {"_id": job}, None).await?;
+    Ok(job_doc
+        .and_then(|d| d.get("last_id").cloned())
+        .and_then(|id| ImageId::try_from(id).ok()))
+}
+
+/// Save the last image id that a job finished processing
+pub async fn set_job_checkpoint(
+    jobs_collection: &Collection<Document>,
+    job: &str,
+    last_id: &ImageId,
+) -> Result<UpdateResult, mongodb::error::Error> {
+    jobs_collection
+        .update_one(
+            doc! {"_id": job},
+            doc! {
+                "$set": {
+                    "last_id": last_id.to_string(),
+                    "updated": bson::DateTime::now(),
+                }
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await
+}
+
+/// Forget the progress of a job so it starts from the beginning next time
+pub async fn clear_job_checkpoint(
+    jobs_collection: &Collection<Document>,
+    job: &str,
+) -> Result<(), mongodb::error::Error> {
+    jobs_collection
+        .delete_one(doc! {"_id": job}, None)
+        .await
+        .map(|_| ())
+}
+
+/// Delete an image, returning whether it existed
+pub async fn delete_image(
+    images_collection: &Collection<Document>,
+    image_id: &ImageId,
+) -> Result<bool, mongodb::error::Error> {
+    let deleted_doc = images_collection
+        .find_one_and_delete(
+            doc! {"_id": image_id.to_string()},
+            FindOneAndDeleteOptions::builder()
+                .projection(doc! {"optim_level": 1})
+                .build(),
+        )
+        .await?;
+    similar::INDEX.write().unwrap().remove(&image_id.0);
+    if let Some(deleted_doc) = &deleted_doc {
+        count_deleted_in_backlog(deleted_doc);
+    }
+    Ok(deleted_doc.is_some())
+}
+
+/// Take a deleted image out of the optimization backlog gauge if it hadn't
+/// been optimized yet.
+fn count_deleted_in_backlog(deleted_doc: &Document) {
+    if deleted_doc.get_i32("optim_level") == Ok(0) {
+        metrics::OPTIMIZATION_BACKLOG.dec();
+    }
+}
+
+/// Delete the images that haven't been viewed in `max_age_millis`, returning
+/// how many were deleted
+pub async fn delete_expired_images(
+    images_collection: &Collection<Document>,
+    max_age_millis: i64,
+) -> Result<u64, mongodb::error::Error> {
+    let target_datetime =
+        bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() - max_age_millis);
+    let expired_filter = doc! {
+        "last_seen": {"$lt": target_datetime},
+        "pinned": {"$ne": true},
+    };
+    // they're deleted one at a time so they can be taken out of the similarity
+    // index too
+    let expired_docs: Vec<Document> = images_collection
+        .find(
+            expired_filter.clone(),
+            FindOptions::builder().projection(doc! {"_id": 1}).build(),
+        )
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut deleted = 0;
+    for expired_doc in expired_docs {
+        let Ok(image_id) = expired_doc.get_str("_id") else {
+            continue;
+        };
+        // the filter is checked again in case it was viewed or pinned since
+        let mut filter = expired_filter.clone();
+        filter.insert("_id", image_id);
+        let deleted_doc = images_collection
+            .find_one_and_delete(
+                filter,
+                FindOneAndDeleteOptions::builder()
+                    .projection(doc! {"optim_level": 1})
+                    .build(),
+            )
+            .await?;
+        if let Some(deleted_doc) = deleted_doc {
+            similar::INDEX.write().unwrap().remove(image_id);
+            count_deleted_in_backlog(&deleted_doc);
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// Keep an image from expiring, or let it expire again. Returns whether the
+/// image exists.
+pub async fn set_image_pinned(
+    images_collection: &Collection<Document>,
+    image_id: &str,
+    pinned: bool,
+) -> Result<bool, mongodb::error::Error> {
+    let update = if pinned {
+        doc! {"$set": {"pinned": true}}
+    } else {
+        doc! {"$unset": {"pinned": ""}}
+    };
+    let result = images_collection
+        .update_one(doc! {"_id": image_id}, update, None)
+        .await?;
+    Ok(result.matched_count > 0)
+}
+
+/// Get images without their full image data, sorted and limited.
+pub async fn find_images_without_data(
+    images_collection: &Collection<Document>,
+    filter: Document,
+    sort: Document,
+    limit: i64,
+) -> Result<Vec<Document>, mongodb::error::Error> {
+    images_collection
+        .find(
+            filter,
+            FindOptions::builder()
+                .projection(doc! {"data": 0})
+                .sort(sort)
+                .limit(limit)
+                .build(),
+        )
+        .await?
+        .try_collect()
+        .await
+}
+
+pub async fn count_images(
+    images_collection: &Collection<Document>,
+    filter: Document,
+) -> Result<u64, mongodb::error::Error> {
+    images_collection.count_documents(filter, None).await
+}
+
+/// Count the images and their sizes, grouped by content type and optimization level
+pub async fn get_storage_stats(
+    images_collection: &Collection<Document>,
+) -> Result<Vec<StorageStats>, mongodb::error::Error> {
+    let pipeline = vec![
+        doc! {
+            "$group": {
+                "_id": {"content_type": "$content_type", "optim_level": "$optim_level"},
+                "count": {"$sum": 1},
+                "bytes": {"$sum": {"$binarySize": "$data"}},
+                "thumbnail_bytes": {"$sum": {"$binarySize": "$thumbnail_data"}},
+            }
+        },
+        doc! {
+            "$sort": {"_id.content_type": 1, "_id.optim_level": 1}
+        },
+    ];
+    let docs: Vec<Document> = images_collection
+        .aggregate(pipeline, None)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(docs
+        .iter()
+        .map(|d| {
+            let group = d.get_document("_id").ok();
+            StorageStats {
+                content_type: group
+                    .and_then(|g| g.get_str("content_type").ok())
+                    .unwrap_or("unknown")
+                    .to_string(),
+                optim_level: group.map(|g| get_number(g, "optim_level")).unwrap_or(0) as u8,
+                count: get_number(d, "count"),
+                bytes: get_number(d, "bytes"),
+                thumbnail_bytes: get_number(d, "thumbnail_bytes"),
+            }
+        })
+        .collect())
+}
+
+/// Store a new API key. Only the hash of the key is saved.
+pub async fn insert_api_key(
+    keys_collection: &Collection<Document>,
+    key_hash: &str,
+    name: &str,
+    admin: bool,
+) -> Result<(), mongodb::error::Error> {
+    keys_collection
+        .insert_one(
+            doc! {
+                "_id": key_hash,
+                "name": name,
+                "admin": admin,
+                "date": bson::DateTime::now(),
+            },
+            None,
+        )
+        .await
+        .map(|_| ())
+}
+
+/// Get the API key with the given hash
+pub async fn get_api_key(
+    keys_collection: &Collection<Document>,
+    key_hash: &str,
+) -> Result<Option<Document>, mongodb::error::Error> {
+    keys_collection.find_one(doc! {"_id": key_hash}, None).await
+}
+
+/// Add a hash to the blocklist. The hash is the id, so adding one that's
+/// already there just updates its reason.
+pub async fn insert_blocklist_entry(
+    blocklist_collection: &Collection<Document>,
+    kind: &str,
+    hash: &str,
+    reason: Option<&str>,
+    source_image: Option<&ImageId>,
+) -> Result<(), mongodb::error::Error> {
+    blocklist_collection
+        .update_one(
+            doc! {"_id": hash},
+            doc! {
+                "$set": {
+                    "kind": kind,
+                    "reason": reason,
+                    "source_image": source_image.map(|id| id.to_string()),
+                },
+                "$setOnInsert": {"date": bson::DateTime::now()},
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await
+        .map(|_| ())
+}
+
+/// Get the blocklist entries, optionally only the ones of one kind.
+pub async fn get_blocklist_entries(
+    blocklist_collection: &Collection<Document>,
+    kind: Option<&str>,
+) -> Result<Vec<Document>, mongodb::error::Error> {
+    let filter = match kind {
+        Some(kind) => doc! {"kind": kind},
+        None => doc! {},
+    };
+    blocklist_collection
+        .find(
+            filter,
+            FindOptions::builder().sort(doc! {"date": 1}).build(),
+        )
+        .await?
+        .try_collect()
+        .await
+}
+
+pub async fn is_blocklisted(
+    blocklist_collection: &Collection<Document>,
+    hash: &str,
+) -> Result<bool, mongodb::error::Error> {
+    Ok(blocklist_collection
+        .find_one(doc! {"_id": hash}, None)
+        .await?
+        .is_some())
+}
+
+/// Remove a hash from the blocklist, returning whether it was there.
+pub async fn delete_blocklist_entry(
+    blocklist_collection: &Collection<Document>,
+    hash: &str,
+) -> Result<bool, mongodb::error::Error> {
+    let result = blocklist_collection
+        .delete_one(doc! {"_id": hash}, None)
+        .await?;
+    Ok(result.deleted_count > 0)
+}
+
+/// Save a report about an image, returning the id of the report.
+pub async fn insert_report(
+    reports_collection: &Collection<Document>,
+    image_id: &str,
+    reason: &str,
+    reporter: Option<&str>,
+) -> Result<String, mongodb::error::Error> {
+    let id = util::generate_random_id(16).0;
+    reports_collection
+        .insert_one(
+            doc! {
+                "_id": &id,
+                "image": image_id,
+                "reason": reason,
+                "reporter": reporter,
+                "status": "open",
+                "date": bson::DateTime::now(),
+            },
+            None,
+        )
+        .await?;
+    Ok(id)
+}
+
+/// Get the reports with the given status, oldest first.
+pub async fn get_reports(
+    reports_collection: &Collection<Document>,
+    status: &str,
+    limit: i64,
+) -> Result<Vec<Document>, mongodb::error::Error> {
+    reports_collection
+        .find(
+            doc! {"status": status},
+            FindOptions::builder()
+                .sort(doc! {"date": 1})
+                .limit(limit)
+                .build(),
+        )
+        .await?
+        .try_collect()
+        .await
+}
+
+pub async fn get_report(
+    reports_collection: &Collection<Document>,
+    id: &str,
+) -> Result<Option<Document>, mongodb::error::Error> {
+    reports_collection.find_one(doc! {"_id": id}, None).await
+}
+
+/// Close every open report about an image with the given status, returning
+/// how many there were.
+pub async fn resolve_reports(
+    reports_collection: &Collection<Document>,
+    image_id: &str,
+    status: &str,
+    moderator: &str,
+) -> Result<u64, mongodb::error::Error> {
+    let result = reports_collection
+        .update_many(
+            doc! {"image": image_id, "status": "open"},
+            doc! {
+                "$set": {
+                    "status": status,
+                    "resolved_by": moderator,
+                    "resolved_at": bson::DateTime::now(),
+                }
+            },
+            None,
+        )
+        .await?;
+    Ok(result.modified_count)
+}
+
+/// Stop or start serving an image, without deleting its data. Returns
+/// whether the image exists.
+pub async fn set_image_disabled(
+    images_collection: &Collection<Document>,
+    image_id: &str,
+    disabled: bool,
+) -> Result<bool, mongodb::error::Error> {
+    let update = if disabled {
+        doc! {"$set": {"disabled": true}}
+    } else {
+        doc! {"$unset": {"disabled": ""}}
+    };
+    let result = images_collection
+        .update_one(doc! {"_id": image_id}, update, None)
+        .await?;
+    Ok(result.matched_count > 0)
+}
+
+/// Record something a moderator did.
+pub async fn insert_audit_log_entry(
+    audit_log_collection: &Collection<Document>,
+    moderator: &str,
+    action: &str,
+    image_id: &str,
+    report_id: Option<&str>,
+) -> Result<(), mongodb::error::Error> {
+    audit_log_collection
+        .insert_one(
+            doc! {
+                "date": bson::DateTime::now(),
+                "moderator": moderator,
+                "action": action,
+                "image": image_id,
+                "report": report_id,
+            },
+            None,
+        )
+        .await
+        .map(|_| ())
+}
+
+/// Get the most recent moderator actions, newest first.
+pub async fn get_audit_log(
+    audit_log_collection: &Collection<Document>,
+    limit: i64,
+) -> Result<Vec<Document>, mongodb::error::Error> {
+    audit_log_collection
+        .find(
+            doc! {},
+            FindOptions::builder()
+                .sort(doc! {"date": -1})
+                .limit(limit)
+                .build(),
+        )
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Get a number from a document, whether MongoDB decided to store it as an
+/// int32, int64 or double
+fn get_number(doc: &Document, key: &str) -> i64 {
+    match doc.get(key) {
+        Some(Bson::Int32(n)) => *n as i64,
+        Some(Bson::Int64(n)) => *n,
+        Some(Bson::Double(n)) => *n as i64,
+        _ => 0,
+    }
+}
+
+/// Create an album with the images in the given order, returning its id. The
+/// id is picked like an image's, so albums of unlisted images get long ids
+/// too.
+pub async fn create_album(
+    albums_collection: &Collection<Document>,
+    id_config: &IdConfig,
+    visibility: Visibility,
+    title: Option<&str>,
+    image_ids: &[ImageId],
+) -> Result<ImageId, mongodb::error::Error> {
+    let mut collisions = ids::Collisions::new(id_config, visibility);
+    loop {
+        let id = ids::random_id(id_config, visibility);
+        let result = albums_collection
+            .insert_one(
+                doc! {
+                    "_id": id.clone(),
+                    "title": title,
+                    "images": image_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>(),
+                    "date": bson::DateTime::now(),
+                },
+                None,
+            )
+            .await;
+        match result {
+            Ok(_) => return Ok(id),
+            // the id was taken, try again with a different one
+            Err(e) if is_duplicate_key_error(&e) => collisions.record(&id),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub async fn get_album(
+    albums_collection: &Collection<Document>,
+    id: &str,
+) -> Result<Option<Document>, mongodb::error::Error> {
+    albums_collection.find_one(doc! {"_id": id}, None).await
+}
+
+/// Get the documents for several images without their full image data, in
+/// the order of the given ids. Images that don't exist are left out.
+pub async fn get_images_without_data(
+    images_collection: &Collection<Document>,
+    ids: &[String],
+) -> Result<Vec<Document>, mongodb::error::Error> {
+    let docs: Vec<Document> = images_collection
+        .find(
+            doc! {"_id": {"$in": ids}},
+            FindOptions::builder().projection(doc! {"data": 0}).build(),
+        )
+        .await?
+        .try_collect()
+        .await?;
+    Ok(ids
+        .iter()
+        .filter_map(|id| docs.iter().find(|d| d.get_str("_id") == Ok(id)).cloned())
+        .collect())
+}
+
+/// The database at `TEST_MONGODB_URI`, for tests that need a real one.
+#[cfg(test)]
+pub async fn test_collections() -> (DatabaseConfig, Collections) {
+    let config = DatabaseConfig {
+        uri: std::env::var("TEST_MONGODB_URI").expect("TEST_MONGODB_URI isn't set"),
+        name: "image_host_test".to_string(),
+    };
+    let collections = connect(&config).await.unwrap();
+    (config, collections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_image_stores_width_and_height() {
+        let id = ImageId("abcde".to_string());
+        let data = vec![1, 2, 3];
+        let thumbnail_data = vec![4];
+        let image = NewImage {
+            id: &id,
+            size: (640, 480),
+            optim_level: 1,
+            data: &data,
+            content_type: "image/png",
+            thumbnail_data: &thumbnail_data,
+            thumbnail_content_type: "image/webp",
+            placeholder: None,
+            perceptual_hash: Some(u64::MAX),
+        };
+
+        let set = image.to_set_document();
+        assert_eq!(set.get_i32("width").unwrap(), 640);
+        assert_eq!(set.get_i32("height").unwrap(), 480);
+        assert_eq!(set.get_i32("optim_level").unwrap(), 1);
+        assert_eq!(set.get_binary_generic("data").unwrap(), &data);
+        // a missing placeholder shouldn't remove the one that's already stored
+        assert!(!set.contains_key("blurhash"));
+        assert_eq!(
+            similar::hash_from_doc(&set),
+            Some(u64::MAX),
+            "perceptual hashes should survive being stored as i64"
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server at TEST_MONGODB_URI"]
+    async fn expired_images_leave_the_similarity_index() {
+        let (_, collections) = test_collections().await;
+        let image_id = ImageId("expired-test".to_string());
+        delete_image(&collections.images, &image_id).await.unwrap();
+        let long_ago = bson::DateTime::from_millis(0);
+        collections
+            .images
+            .insert_one(
+                doc! {"_id": &image_id.0, "last_seen": long_ago, "dhash": 42i64},
+                None,
+            )
+            .await
+            .unwrap();
+        similar::INDEX.write().unwrap().insert(&image_id.0, 42);
+
+        assert!(
+            delete_expired_images(&collections.images, 1000)
+                .await
+                .unwrap()
+                >= 1
+        );
+        assert!(get_image(&collections.images, &image_id.0)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(similar::INDEX.read().unwrap().get(&image_id.0), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server at TEST_MONGODB_URI"]
+    async fn updates_dont_bring_back_deleted_images() {
+        let (_, collections) = test_collections().await;
+        let image_id = ImageId("update-test".to_string());
+        let data = vec![1, 2, 3];
+        let image = NewImage {
+            id: &image_id,
+            size: (1, 1),
+            optim_level: 0,
+            data: &data,
+            content_type: "image/png",
+            thumbnail_data: &data,
+            thumbnail_content_type: "image/png",
+            placeholder: None,
+            perceptual_hash: None,
+        };
+        delete_image(&collections.images, &image_id).await.unwrap();
+        insert_new_image(&collections.images, &image, Visibility::Private, "", None)
+            .await
+            .unwrap();
+
+        let optimized = NewImage {
+            optim_level: 1,
+            ..image
+        };
+        assert!(update_image(&collections.images, &optimized, 0)
+            .await
+            .unwrap());
+        // it's already optimized
+        assert!(!update_image(&collections.images, &optimized, 0)
+            .await
+            .unwrap());
+        let image_doc = get_image(&collections.images, &image_id.0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(image_doc.get_str("visibility").unwrap(), "private");
+
+        delete_image(&collections.images, &image_id).await.unwrap();
+        assert!(!update_image(&collections.images, &optimized, 1)
+            .await
+            .unwrap());
+        assert!(get_image(&collections.images, &image_id.0)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
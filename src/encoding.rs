@@ -1,52 +1,229 @@
 //! Encode images into the formats that we use
 
-use crate::util;
+use crate::config::DecodingConfig;
+use crate::error::UploadError;
+use crate::{metrics, similar, util};
 use futures::future::join_all;
 use image::imageops::FilterType;
-use image::io::Reader as ImageReader;
+use image::io::{Limits, Reader as ImageReader};
 use image::DynamicImage;
 use image::GenericImageView;
-use std::io::Cursor;
+use image::{ImageError, ImageFormat, ImageResult};
+use std::io::{BufRead, Cursor, Seek};
+use std::path::Path;
+use std::sync::OnceLock;
 use std::{fmt::Debug, path::PathBuf};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Semaphore;
 use tokio::task;
 use tokio::task::JoinHandle;
+use tracing::{debug, info_span, Instrument};
+
+/// The limits from `decoding` in the config. Decoding happens in too many
+/// places to pass the config to all of them, so it's set once at startup with
+/// [`configure`].
+static DECODING: OnceLock<Decoding> = OnceLock::new();
+
+struct Decoding {
+    limits: DecodeLimits,
+    /// Decoding uses a lot of memory, so only this many images are decoded at
+    /// the same time.
+    permits: Semaphore,
+}
+
+impl Decoding {
+    fn new(config: &DecodingConfig) -> Decoding {
+        Decoding {
+            limits: DecodeLimits {
+                max_width: config.max_width,
+                max_height: config.max_height,
+                max_alloc: config.max_alloc,
+            },
+            permits: Semaphore::new(config.max_concurrent),
+        }
+    }
+
+    fn get() -> &'static Decoding {
+        DECODING.get_or_init(|| Decoding::new(&DecodingConfig::default()))
+    }
+}
+
+/// Use the limits from the config for decoding. This has to be called before
+/// anything is decoded, or the defaults are used.
+pub fn configure(config: &DecodingConfig) {
+    if DECODING.set(Decoding::new(config)).is_err() {
+        tracing::warn!("Decoding was already configured");
+    }
+}
+
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// The most bytes a decoded image can take up
+    pub max_alloc: u64,
+}
+
+impl DecodeLimits {
+    fn image_limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+
+    /// Check whether an image with these dimensions is small enough to decode.
+    pub fn check_dimensions(&self, width: u32, height: u32) -> Result<(), String> {
+        if width > self.max_width || height > self.max_height {
+            return Err(format!(
+                "The image is {}x{}, the biggest allowed is {}x{}",
+                width, height, self.max_width, self.max_height
+            ));
+        }
+        // we usually end up with 4 bytes per pixel since we convert to rgba
+        if width as u64 * height as u64 * 4 > self.max_alloc {
+            return Err(format!(
+                "The image is {}x{}, which is too big to decode",
+                width, height
+            ));
+        }
+        Ok(())
+    }
+}
 
 pub struct EncodeResult {
     pub data: Vec<u8>,
     pub size: (u32, u32),
     pub content_type: String,
+    /// Only set if `placeholder` was enabled in the options
+    pub placeholder: Option<Placeholder>,
+    /// Only set if `perceptual_hash` was enabled in the options
+    pub perceptual_hash: Option<u64>,
 }
 
-/// Encode an image as a Webp from the given file path
-pub async fn image_path_to_encoded(
-    path: Box<PathBuf>,
-    content_type: &'_ str,
-    opts: FromImageOptions,
-) -> Result<EncodeResult, String> {
-    info!("reading file");
-    // read the bytes of the file into an ImageReader
+/// A tiny stand-in for an image that can be shown before it loads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placeholder {
+    /// A [BlurHash](https://blurha.sh) of the image
+    pub blurhash: String,
+    /// The average color of the image, like `#ff1493`
+    pub average_color: String,
+}
 
-    let read_image = task::spawn_blocking(move || ImageReader::open(*path))
+impl Placeholder {
+    /// Compute the placeholder for an image. This is slow for big images, so
+    /// it should be given something thumbnail-sized.
+    pub fn from_image(im: &DynamicImage) -> Result<Placeholder, String> {
+        let rgba = im.to_rgba8();
+        let (width, height) = rgba.dimensions();
+        let blurhash =
+            blurhash::encode(4, 3, width, height, rgba.as_raw()).map_err(|e| e.to_string())?;
+
+        // weight by alpha so transparent pixels don't make the color darker
+        let mut sums = [0u64; 3];
+        let mut total_alpha = 0u64;
+        for pixel in rgba.pixels() {
+            let alpha = pixel[3] as u64;
+            for (sum, channel) in sums.iter_mut().zip(pixel.0) {
+                *sum += channel as u64 * alpha;
+            }
+            total_alpha += alpha;
+        }
+        let [r, g, b] = sums.map(|sum| (sum / total_alpha.max(1)) as u8);
+
+        Ok(Placeholder {
+            blurhash,
+            average_color: format!("#{:02x}{:02x}{:02x}", r, g, b),
+        })
+    }
+}
+
+/// Figure out the format of an uploaded file from its first bytes, making sure
+/// it matches the content type the client sent.
+pub async fn detect_format(
+    path: &Path,
+    claimed_content_type: Option<&str>,
+) -> Result<ImageFormat, UploadError> {
+    let mut header = Vec::with_capacity(64);
+    File::open(path)
         .await
-        .unwrap();
+        .map_err(|e| e.to_string())?
+        .take(64)
+        .read_to_end(&mut header)
+        .await
+        .map_err(|e| e.to_string())?;
 
-    let mut read_image = match read_image {
-        Ok(read_image) => read_image,
-        Err(e) => return Err(e.to_string()),
-    };
-    info!("decoding");
+    util::check_upload_format(image::guess_format(&header).ok(), claimed_content_type)
+        .map_err(UploadError::UnsupportedMediaType)
+}
 
-    // set the format of the ImageReader to the format of the image
-    read_image.set_format(util::mimetype_to_format(content_type));
+/// Make sure an uploaded image isn't too big to decode, by only reading its
+/// header. Returns the dimensions of the image.
+pub async fn check_dimensions(path: &Path, format: ImageFormat) -> Result<(u32, u32), UploadError> {
+    let path = path.to_path_buf();
+    let (width, height) = task::spawn_blocking(move || {
+        let mut read_image = ImageReader::open(path)?;
+        read_image.set_format(format);
+        read_image.into_dimensions()
+    })
+    .await
+    .unwrap()
+    .map_err(|e| UploadError::UnsupportedMediaType(format!("Error reading image: {}", e)))?;
+
+    Decoding::get()
+        .limits
+        .check_dimensions(width, height)
+        .map_err(UploadError::PayloadTooLarge)?;
+    Ok((width, height))
+}
 
-    let decoded_image: DynamicImage = task::spawn_blocking(move || read_image.decode())
+/// Decode an image with the limits from the config applied, waiting first if
+/// too many images are already being decoded.
+pub async fn decode_with_limits<R>(mut read_image: ImageReader<R>) -> ImageResult<DynamicImage>
+where
+    R: BufRead + Seek + Send + 'static,
+{
+    let decoding = Decoding::get();
+    read_image.limits(decoding.limits.image_limits());
+    let _permit = decoding.permits.acquire().await.unwrap();
+    task::spawn_blocking(move || read_image.decode())
         .await
         .unwrap()
-        .map_err(|_| "Error decoding image".to_string())?;
+}
 
-    info!("decoded file");
+/// Encode an image as a Webp from the given file path. Files that can't be
+/// decoded, like ones that were cut off partway through, are the client's
+/// fault and not ours.
+pub async fn image_path_to_encoded(
+    path: Box<PathBuf>,
+    format: ImageFormat,
+    opts: FromImageOptions,
+) -> Result<EncodeResult, UploadError> {
+    let decoded_image: DynamicImage = async {
+        // read the bytes of the file into an ImageReader
+        let read_image = task::spawn_blocking(move || ImageReader::open(*path))
+            .await
+            .unwrap();
+
+        let mut read_image = match read_image {
+            Ok(read_image) => read_image,
+            Err(e) => return Err(UploadError::Internal(e.to_string())),
+        };
 
-    from_image(decoded_image, opts).await
+        read_image.set_format(format);
+
+        decode_with_limits(read_image).await.map_err(|e| match e {
+            ImageError::Limits(e) => {
+                UploadError::PayloadTooLarge(format!("Image is too big: {}", e))
+            }
+            _ => UploadError::UnsupportedMediaType("Error decoding image".to_string()),
+        })
+    }
+    .instrument(info_span!("decode"))
+    .await?;
+
+    Ok(from_image(decoded_image, opts).await?)
 }
 
 struct CompressedImageResult {
@@ -55,14 +232,12 @@ struct CompressedImageResult {
 }
 
 /// Convert a dynamic image into a Webp
-fn to_webp(im: &DynamicImage) -> Result<CompressedImageResult, String> {
-    info!("encoding webp");
+fn to_webp(im: &DynamicImage, quality: f32) -> Result<CompressedImageResult, String> {
     let encoder = match webp::Encoder::from_image(im) {
         Ok(i) => i,
-        Err(e) => return Err(format!("Error making encoder for webp: {}", e.to_string())),
+        Err(e) => return Err(format!("Error making encoder for webp: {}", e)),
     };
-    let image_bytes = (*encoder.encode(90.0)).to_vec();
-    info!("encoded webp");
+    let image_bytes = (*encoder.encode(quality)).to_vec();
 
     Ok(CompressedImageResult {
         data: image_bytes,
@@ -75,12 +250,12 @@ fn to_png(im: &DynamicImage) -> Result<CompressedImageResult, String> {
     let mut bytes: Cursor<Vec<u8>> = Cursor::new(Vec::new());
     match im.write_to(&mut bytes, image::ImageOutputFormat::Png) {
         Ok(_) => (),
-        Err(e) => return Err(format!("Error writing png: {}", e.to_string())),
+        Err(e) => return Err(format!("Error writing png: {}", e)),
     };
     let image_bytes =
         match oxipng::optimize_from_memory(&bytes.into_inner()[..], &oxipng::Options::default()) {
             Ok(r) => r,
-            Err(e) => return Err(format!("Error optimizing png: {}", e.to_string())),
+            Err(e) => return Err(format!("Error optimizing png: {}", e)),
         };
 
     Ok(CompressedImageResult {
@@ -96,13 +271,23 @@ pub struct FromImageOptions {
     pub max_size: Option<u32>,
     /// Whether it should also try compressing the image with PNG in parallel, this will be slower and often unnecessary
     pub optimize_png: bool,
+    /// Whether to compute a blurhash and average color of the (resized) image
+    pub placeholder: bool,
+    /// Whether to compute a perceptual hash of the (resized) image, for
+    /// finding similar images
+    pub perceptual_hash: bool,
+    /// From 0 to 100
+    pub webp_quality: f32,
 }
 
 impl Default for FromImageOptions {
-    fn default() -> FromImageOptions {
+    fn default() -> Self {
         FromImageOptions {
             max_size: None,
             optimize_png: false,
+            placeholder: false,
+            perceptual_hash: false,
+            webp_quality: 90.0,
         }
     }
 }
@@ -126,6 +311,7 @@ fn clamp_im_size(width: u32, height: u32, max_size: u32) -> (u32, u32) {
 }
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod tests {
     use super::*;
     #[test]
@@ -143,6 +329,52 @@ mod tests {
         let (w, h) = clamp_im_size(256, 64, 16);
         assert_eq!((w, h), (16, 4));
     }
+    #[tokio::test]
+    async fn truncated_images_are_unsupported() {
+        let image = DynamicImage::new_rgb8(64, 64);
+        let mut bytes = Cursor::new(Vec::new());
+        image
+            .write_to(&mut bytes, image::ImageOutputFormat::Png)
+            .unwrap();
+        let bytes = bytes.into_inner();
+        let path =
+            std::env::temp_dir().join(format!("image-host-test-{}.png", util::generate_api_key()));
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+
+        let result = image_path_to_encoded(
+            Box::new(path.clone()),
+            ImageFormat::Png,
+            FromImageOptions::default(),
+        )
+        .await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(UploadError::UnsupportedMediaType(_))));
+    }
+
+    #[test]
+    fn decode_limits_reject_huge_images() {
+        let limits = DecodeLimits {
+            max_width: 1000,
+            max_height: 1000,
+            max_alloc: 1000 * 500 * 4,
+        };
+        assert!(limits.check_dimensions(1000, 500).is_ok());
+        assert!(limits.check_dimensions(1001, 10).is_err());
+        assert!(limits.check_dimensions(10, 60000).is_err());
+        assert!(limits.check_dimensions(1000, 1000).is_err());
+    }
+    #[test]
+    fn placeholder_of_solid_color() {
+        let im = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            16,
+            8,
+            image::Rgba([255, 20, 147, 255]),
+        ));
+        let placeholder = Placeholder::from_image(&im).unwrap();
+        assert_eq!(placeholder.average_color, "#ff1493");
+        // 4x3 components is 1 + 1 + 4 + 2 * 11 characters
+        assert_eq!(placeholder.blurhash.len(), 28);
+    }
     #[test]
     fn clamp_im_uneven() {
         let (w, h) = clamp_im_size(112, 398, 256);
@@ -151,13 +383,11 @@ mod tests {
 }
 
 /// Convert a dynamic image into an optimized image
-pub async fn from_image<'a>(
+pub async fn from_image(
     original_im: DynamicImage,
     opts: FromImageOptions,
 ) -> Result<EncodeResult, String> {
-    info!("from_image {:?}", opts);
     let (original_width, original_height) = original_im.dimensions();
-    info!("dimensions: {} {}", original_width, original_height);
 
     // if the image is too big, resize it to be 512x512
     let (size, im) = if let Some(max_size) = opts.max_size {
@@ -168,8 +398,11 @@ pub async fn from_image<'a>(
             // task::spawn_blocking(move || im.resize_exact(512, 512, FilterType::Nearest))
             //     .await
             //     .unwrap();
+            let span = info_span!("resize", width = new_size.0, height = new_size.1);
             let new_im = task::spawn_blocking(move || {
-                original_im.resize_exact(new_size.0, new_size.1, FilterType::Lanczos3)
+                span.in_scope(|| {
+                    original_im.resize_exact(new_size.0, new_size.1, FilterType::Lanczos3)
+                })
             })
             .await
             .unwrap();
@@ -182,25 +415,48 @@ pub async fn from_image<'a>(
         ((original_width, original_height), original_im)
     };
 
-    info!("did resize step");
-
     // we have to clone `im` because it will get moved
     // it's probably possible to not have to clone but i don't think it matters
-    info!("cloning");
     let webp_im = im.clone();
     let png_im = im.clone();
-    info!("cloned, now creating futures (this should be instant)");
 
+    let webp_quality = opts.webp_quality;
+    let webp_span = info_span!("encode", encoder = "webp");
     let mut futures: Vec<JoinHandle<Result<CompressedImageResult, String>>> =
-        vec![task::spawn_blocking(move || to_webp(&webp_im))];
+        vec![task::spawn_blocking(move || {
+            webp_span.in_scope(|| {
+                metrics::ENCODE_SECONDS
+                    .with_label_values(&["webp"])
+                    .observe_closure_duration(|| to_webp(&webp_im, webp_quality))
+            })
+        })];
 
     if opts.optimize_png {
-        futures.push(task::spawn_blocking(move || to_png(&png_im)));
+        let png_span = info_span!("encode", encoder = "png");
+        futures.push(task::spawn_blocking(move || {
+            png_span.in_scope(|| {
+                metrics::ENCODE_SECONDS
+                    .with_label_values(&["png"])
+                    .observe_closure_duration(|| to_png(&png_im))
+            })
+        }));
     }
-    info!("created futures; joining");
+    let placeholder_future = if opts.placeholder {
+        let placeholder_im = im.clone();
+        Some(task::spawn_blocking(move || {
+            Placeholder::from_image(&placeholder_im)
+        }))
+    } else {
+        None
+    };
+    let perceptual_hash_future = if opts.perceptual_hash {
+        let hash_im = im.clone();
+        Some(task::spawn_blocking(move || similar::dhash(&hash_im)))
+    } else {
+        None
+    };
     // unbox the futures and join them
     let future_results = join_all(futures).await;
-    info!("Did compression");
 
     // unwrap the first set of results
     let future_results: Vec<_> = future_results.iter().map(|r| r.as_ref().unwrap()).collect();
@@ -212,11 +468,25 @@ pub async fn from_image<'a>(
         .map(|r| r.as_ref().unwrap())
         .min_by_key(|r| r.data.len())
         .unwrap();
-    info!("finished from_image {:?}", opts);
+    let placeholder = match placeholder_future {
+        Some(future) => Some(future.await.map_err(|e| e.to_string())??),
+        None => None,
+    };
+    let perceptual_hash = match perceptual_hash_future {
+        Some(future) => Some(future.await.map_err(|e| e.to_string())?),
+        None => None,
+    };
+    debug!(
+        content_type = compressed_image_result.content_type,
+        bytes = compressed_image_result.data.len(),
+        "encoded image"
+    );
 
     Ok(EncodeResult {
         data: compressed_image_result.data.to_vec(),
         size,
         content_type: compressed_image_result.content_type.to_string(),
+        placeholder,
+        perceptual_hash,
     })
 }
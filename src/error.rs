@@ -0,0 +1,84 @@
+//! Errors that get sent back to the client with a status code.
+
+use rocket::http::Status;
+use rocket::response::{self, status, Responder};
+use rocket::Request;
+use std::fmt;
+
+/// An error from uploading an image.
+#[derive(Debug)]
+pub enum UploadError {
+    /// The request was missing the image or was malformed
+    BadRequest(String),
+    /// The file isn't an image we can decode, or isn't the format the client
+    /// said it was
+    UnsupportedMediaType(String),
+    /// The image (or whatever else was asked for) doesn't exist
+    NotFound(String),
+    /// The client isn't allowed to do this, like uploading from a private address
+    Forbidden(String),
+    /// The id the client asked for is already taken
+    Conflict(String),
+    /// The image is too big for us to decode
+    PayloadTooLarge(String),
+    /// The image is on the blocklist
+    UnavailableForLegalReasons(String),
+    /// Another server we had to talk to failed, like when uploading from a url
+    BadGateway(String),
+    /// Something went wrong on our side
+    Internal(String),
+}
+
+impl UploadError {
+    pub fn status(&self) -> Status {
+        match self {
+            UploadError::BadRequest(_) => Status::BadRequest,
+            UploadError::UnsupportedMediaType(_) => Status::UnsupportedMediaType,
+            UploadError::NotFound(_) => Status::NotFound,
+            UploadError::Forbidden(_) => Status::Forbidden,
+            UploadError::Conflict(_) => Status::Conflict,
+            UploadError::PayloadTooLarge(_) => Status::PayloadTooLarge,
+            UploadError::UnavailableForLegalReasons(_) => Status::UnavailableForLegalReasons,
+            UploadError::BadGateway(_) => Status::BadGateway,
+            UploadError::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            UploadError::BadRequest(message)
+            | UploadError::UnsupportedMediaType(message)
+            | UploadError::NotFound(message)
+            | UploadError::Forbidden(message)
+            | UploadError::Conflict(message)
+            | UploadError::PayloadTooLarge(message)
+            | UploadError::UnavailableForLegalReasons(message)
+            | UploadError::BadGateway(message)
+            | UploadError::Internal(message) => message,
+        }
+    }
+}
+
+impl From<String> for UploadError {
+    fn from(message: String) -> Self {
+        UploadError::Internal(message)
+    }
+}
+
+impl From<mongodb::error::Error> for UploadError {
+    fn from(error: mongodb::error::Error) -> Self {
+        UploadError::Internal(error.to_string())
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for UploadError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        status::Custom(self.status(), self.message().to_string()).respond_to(request)
+    }
+}
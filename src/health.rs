@@ -0,0 +1,270 @@
+//! `/healthz` and `/readyz`, for container orchestrators to check on the
+//! server. `/healthz` only says the process is up, `/readyz` also checks that
+//! everything needed for handling uploads works.
+
+use crate::config::Config;
+use crate::db;
+use crate::tus::TusStore;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{Route, State};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::error;
+
+/// How long pinging the database can take before it counts as unreachable.
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The background tasks the server started. Loops should keep running for as
+/// long as the server does, and one-shot jobs (like loading the similarity
+/// index) should finish without an error. If a loop stops or a job fails
+/// (like from a panic), the server isn't ready.
+#[derive(Default)]
+pub struct BackgroundTasks {
+    tasks: Mutex<Vec<Task>>,
+}
+
+struct Task {
+    name: &'static str,
+    handle: JoinHandle<()>,
+    /// Set when a one-shot job finishes without an error, and None for loops
+    completed: Option<Arc<AtomicBool>>,
+}
+
+/// The names of the tasks in each state.
+#[derive(Debug, Default, PartialEq)]
+struct TaskStatuses {
+    running: Vec<&'static str>,
+    /// One-shot jobs that finished without an error
+    completed: Vec<&'static str>,
+    /// Loops that stopped and jobs that failed
+    stopped: Vec<&'static str>,
+}
+
+impl BackgroundTasks {
+    /// Start a loop that should never stop.
+    pub fn spawn<F>(&self, name: &'static str, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let handle = tokio::spawn(future);
+        self.tasks.lock().unwrap().push(Task {
+            name,
+            handle,
+            completed: None,
+        });
+    }
+
+    /// Start a job that runs once. Errors are logged.
+    pub fn spawn_once<F>(&self, name: &'static str, future: F)
+    where
+        F: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let completed = Arc::new(AtomicBool::new(false));
+        let owned_completed = completed.clone();
+        let handle = tokio::spawn(async move {
+            match future.await {
+                Ok(()) => owned_completed.store(true, Ordering::Relaxed),
+                Err(e) => error!(task = name, error = %e, "Background job failed"),
+            }
+        });
+        self.tasks.lock().unwrap().push(Task {
+            name,
+            handle,
+            completed: Some(completed),
+        });
+    }
+
+    fn statuses(&self) -> TaskStatuses {
+        let mut statuses = TaskStatuses::default();
+        for task in self.tasks.lock().unwrap().iter() {
+            let list = if !task.handle.is_finished() {
+                &mut statuses.running
+            } else if task
+                .completed
+                .as_ref()
+                .is_some_and(|completed| completed.load(Ordering::Relaxed))
+            {
+                &mut statuses.completed
+            } else {
+                &mut statuses.stopped
+            };
+            list.push(task.name);
+        }
+        statuses
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Check {
+    ok: bool,
+    /// What was found, or what went wrong
+    detail: String,
+}
+
+impl Check {
+    fn new(ok: bool, detail: String) -> Check {
+        Check { ok, detail }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Health {
+    ok: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+impl Health {
+    fn new(checks: BTreeMap<&'static str, Check>) -> Health {
+        Health {
+            ok: checks.values().all(|check| check.ok),
+            checks,
+        }
+    }
+
+    fn status(&self) -> Status {
+        if self.ok {
+            Status::Ok
+        } else {
+            Status::ServiceUnavailable
+        }
+    }
+}
+
+async fn check_database(collections: &db::Collections) -> Check {
+    match tokio::time::timeout(PING_TIMEOUT, db::ping(&collections.images)).await {
+        Ok(Ok(())) => Check::new(true, "reachable".to_string()),
+        // the error can have hostnames and such in it, which shouldn't be
+        // public
+        Ok(Err(e)) => {
+            error!(error = %e, "Error pinging the database");
+            Check::new(false, "unreachable".to_string())
+        }
+        Err(_) => Check::new(
+            false,
+            format!("no response after {} seconds", PING_TIMEOUT.as_secs()),
+        ),
+    }
+}
+
+fn check_background_tasks(background_tasks: &BackgroundTasks) -> Check {
+    let statuses = background_tasks.statuses();
+    if !statuses.stopped.is_empty() {
+        return Check::new(false, format!("stopped: {}", statuses.stopped.join(", ")));
+    }
+    let mut detail = format!("running: {}", statuses.running.join(", "));
+    if !statuses.completed.is_empty() {
+        detail.push_str(&format!("; completed: {}", statuses.completed.join(", ")));
+    }
+    Check::new(true, detail)
+}
+
+fn check_disk_space(dir: &Path, min_free_bytes: u64) -> Check {
+    match fs2::available_space(dir) {
+        Ok(free) => Check::new(
+            free >= min_free_bytes,
+            format!("{} bytes free in {}", free, dir.display()),
+        ),
+        Err(e) => Check::new(false, format!("{}: {}", dir.display(), e)),
+    }
+}
+
+/// The process is running. This doesn't check anything else, so an
+/// orchestrator won't restart the server just because the database is down.
+#[get("/healthz")]
+fn healthz_route() -> Json<Health> {
+    Json(Health::new(BTreeMap::new()))
+}
+
+/// Whether the server can handle requests: the database is reachable, the
+/// background tasks are running, and there's space for writing uploads to.
+#[get("/readyz")]
+async fn readyz_route(
+    collections: &State<db::Collections>,
+    background_tasks: &State<BackgroundTasks>,
+    tus_store: &State<Arc<TusStore>>,
+    config: &State<Config>,
+) -> (Status, Json<Health>) {
+    let min_free_bytes = config.health.min_free_disk_bytes;
+    let mut checks = BTreeMap::new();
+    checks.insert("database", check_database(collections).await);
+    checks.insert("background_tasks", check_background_tasks(background_tasks));
+    checks.insert(
+        "temp_disk",
+        check_disk_space(&std::env::temp_dir(), min_free_bytes),
+    );
+    checks.insert(
+        "tus_disk",
+        check_disk_space(tus_store.dir(), min_free_bytes),
+    );
+
+    let health = Health::new(checks);
+    (health.status(), Json(health))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![healthz_route, readyz_route]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn stopped_tasks_are_reported() {
+        let background_tasks = BackgroundTasks::default();
+        background_tasks.spawn("finishes", async {});
+        background_tasks.spawn("runs", std::future::pending());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            background_tasks.statuses(),
+            TaskStatuses {
+                running: vec!["runs"],
+                completed: vec![],
+                stopped: vec!["finishes"],
+            }
+        );
+        let check = check_background_tasks(&background_tasks);
+        assert!(!check.ok);
+        assert_eq!(check.detail, "stopped: finishes");
+    }
+
+    #[rocket::async_test]
+    async fn finished_jobs_are_ok_unless_they_failed() {
+        let background_tasks = BackgroundTasks::default();
+        background_tasks.spawn("runs", std::future::pending());
+        background_tasks.spawn_once("works", async { Ok(()) });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let check = check_background_tasks(&background_tasks);
+        assert!(check.ok);
+        assert_eq!(check.detail, "running: runs; completed: works");
+
+        background_tasks.spawn_once("fails", async { Err("oops".to_string()) });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let check = check_background_tasks(&background_tasks);
+        assert!(!check.ok);
+        assert_eq!(check.detail, "stopped: fails");
+    }
+
+    #[test]
+    fn unhealthy_if_any_check_fails() {
+        let mut checks = BTreeMap::new();
+        checks.insert("a", Check::new(true, String::new()));
+        assert_eq!(Health::new(checks).status(), Status::Ok);
+
+        let mut checks = BTreeMap::new();
+        checks.insert("a", Check::new(true, String::new()));
+        checks.insert("b", Check::new(false, String::new()));
+        assert_eq!(Health::new(checks).status(), Status::ServiceUnavailable);
+    }
+}
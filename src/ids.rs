@@ -0,0 +1,166 @@
+//! Picking ids for new images. Most get a random id from the configured
+//! alphabet, but uploaders with an API key can ask for their own. Unlisted and
+//! private images get long random ids so they can't be guessed.
+
+use crate::auth::ApiKey;
+use crate::config::IdConfig;
+use crate::error::UploadError;
+use crate::util::{self, ImageId};
+use crate::visibility::Visibility;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::warn;
+
+/// Custom ids that would be shadowed by other routes.
+const RESERVED_IDS: &[&str] = &["admin", "api", "healthz", "metrics", "oembed", "readyz"];
+
+/// How many characters longer than `ids.length` random ids are right now.
+/// This only goes up, when uploads keep picking ids that are taken.
+static EXTRA_LENGTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Generate a random id that probably isn't taken yet.
+pub fn random_id(config: &IdConfig, visibility: Visibility) -> ImageId {
+    let length = match visibility {
+        Visibility::Public => config.length + EXTRA_LENGTH.load(Ordering::Relaxed),
+        Visibility::Unlisted | Visibility::Private => config.unlisted_length,
+    };
+    ImageId(util::generate_random_string(
+        length,
+        config.alphabet.as_bytes(),
+    ))
+}
+
+/// Keeps track of how many random ids one upload tried that were already
+/// taken, and makes ids longer if there were too many.
+pub struct Collisions<'a> {
+    config: &'a IdConfig,
+    visibility: Visibility,
+    count: u32,
+}
+
+impl<'a> Collisions<'a> {
+    pub fn new(config: &'a IdConfig, visibility: Visibility) -> Self {
+        Collisions {
+            config,
+            visibility,
+            count: 0,
+        }
+    }
+
+    /// Call this when `id` turned out to be taken.
+    pub fn record(&mut self, id: &ImageId) {
+        // unlisted ids are long enough that running out of them isn't a worry
+        if self.visibility != Visibility::Public {
+            return;
+        }
+        self.count += 1;
+        if self.count < self.config.max_collisions {
+            return;
+        }
+        self.count = 0;
+        // if several uploads run out of ids at the same time, only the first
+        // one makes them longer
+        let extra_length = id.0.len().saturating_sub(self.config.length);
+        if EXTRA_LENGTH
+            .compare_exchange(
+                extra_length,
+                extra_length + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            warn!(
+                length = id.0.len() + 1,
+                "Too many id collisions, making ids longer"
+            );
+        }
+    }
+}
+
+/// Whether an uploader can use the id as a custom id.
+pub fn is_valid_custom_id(id: &str) -> bool {
+    (3..=64).contains(&id.len())
+        && id.bytes().all(util::is_id_char)
+        && !RESERVED_IDS.contains(&id.to_ascii_lowercase().as_str())
+}
+
+/// Check the custom id an upload asked for, if it asked for one. Only
+/// uploaders with an API key can pick their ids.
+pub fn custom_id(
+    requested: Option<&str>,
+    api_key: Option<&ApiKey>,
+) -> Result<Option<ImageId>, UploadError> {
+    let Some(requested) = requested.map(str::trim).filter(|id| !id.is_empty()) else {
+        return Ok(None);
+    };
+    if api_key.is_none() {
+        return Err(UploadError::Forbidden(
+            "An API key is needed to pick an id".to_string(),
+        ));
+    }
+    if !is_valid_custom_id(requested) {
+        return Err(UploadError::BadRequest(
+            "Ids must be 3 to 64 letters, numbers, dashes or underscores".to_string(),
+        ));
+    }
+    Ok(Some(ImageId(requested.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_ids_are_checked() {
+        assert!(is_valid_custom_id("team-standup_2"));
+        assert!(!is_valid_custom_id("ab"));
+        assert!(!is_valid_custom_id("has space"));
+        assert!(!is_valid_custom_id("../etc"));
+        assert!(!is_valid_custom_id("Metrics"));
+        assert!(!is_valid_custom_id(&"a".repeat(65)));
+    }
+
+    #[test]
+    fn custom_ids_need_an_api_key() {
+        let api_key = ApiKey {
+            id: "hash".to_string(),
+            name: "test".to_string(),
+            admin: false,
+        };
+        assert!(custom_id(None, None).unwrap().is_none());
+        assert!(custom_id(Some(" "), None).unwrap().is_none());
+        assert!(matches!(
+            custom_id(Some("standup"), None),
+            Err(UploadError::Forbidden(_))
+        ));
+        assert_eq!(
+            custom_id(Some("standup"), Some(&api_key))
+                .unwrap()
+                .unwrap()
+                .0,
+            "standup"
+        );
+    }
+
+    #[test]
+    fn ids_get_longer_after_too_many_collisions() {
+        let config = IdConfig {
+            length: 3,
+            alphabet: "ab".to_string(),
+            max_collisions: 2,
+            unlisted_length: 16,
+        };
+        let public = Visibility::Public;
+        assert_eq!(random_id(&config, public).0.len(), 3);
+        let mut collisions = Collisions::new(&config, public);
+        collisions.record(&random_id(&config, public));
+        assert_eq!(random_id(&config, public).0.len(), 3);
+        collisions.record(&random_id(&config, public));
+        assert_eq!(random_id(&config, public).0.len(), 4);
+        assert!(random_id(&config, public)
+            .0
+            .bytes()
+            .all(|c| c == b'a' || c == b'b'));
+        assert_eq!(random_id(&config, Visibility::Unlisted).0.len(), 16);
+    }
+}
@@ -0,0 +1,226 @@
+//! Bulk import images from the local filesystem, like when migrating an
+//! existing folder of screenshots.
+
+use crate::cli::ImportArgs;
+use crate::config::Config;
+use crate::visibility::Visibility;
+use crate::{db, ids, upload_image_with_id, util};
+use futures::stream::{self, StreamExt};
+use image::io::Reader as ImageReader;
+use image::ImageFormat;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use tokio::task;
+use util::ImageId;
+
+/// What happened when importing one file.
+struct ImportedFile {
+    path: PathBuf,
+    result: Result<ImageId, String>,
+}
+
+/// Import every image in the given files and directories, printing the url of
+/// each one.
+pub async fn import(
+    collections: &db::Collections,
+    config: &Config,
+    args: &ImportArgs,
+) -> Result<(), String> {
+    let mut paths = Vec::new();
+    for path in &args.paths {
+        collect_files(path, &mut paths).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+
+    // figuring out the formats means reading every file, so do it in parallel
+    let explicit_paths = args.paths.clone();
+    let detected: Vec<(PathBuf, Result<ImageFormat, String>)> = task::spawn_blocking(move || {
+        paths
+            .into_par_iter()
+            .map(|path| {
+                let format = detect_format(&path);
+                (path, format)
+            })
+            .collect()
+    })
+    .await
+    .unwrap();
+
+    let mut files = Vec::new();
+    for (path, format) in detected {
+        match format {
+            Ok(format) => files.push((path, format)),
+            // files in directories that aren't images are ignored, but if you
+            // asked for a specific file then it's an error
+            Err(e) if explicit_paths.contains(&path) => {
+                println!("{}: {}", path.display(), e);
+            }
+            Err(_) => {}
+        }
+    }
+
+    let mut imported: Vec<ImportedFile> = stream::iter(files)
+        .map(|(path, format)| async move {
+            let result =
+                import_file(collections, config, &path, format, args.ids_from_filenames).await;
+            match &result {
+                Ok(image_id) => {
+                    println!("{}: {}", path.display(), image_url(image_id, &config.host))
+                }
+                Err(e) => println!("{}: {}", path.display(), e),
+            }
+            ImportedFile { path, result }
+        })
+        .buffer_unordered(args.concurrency.max(1))
+        .collect()
+        .await;
+    imported.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if let Some(csv_path) = &args.csv {
+        write_csv(csv_path, &imported, &config.host).map_err(|e| e.to_string())?;
+    }
+
+    let failed = imported.iter().filter(|f| f.result.is_err()).count();
+    println!(
+        "Imported {} images, {} failed",
+        imported.len() - failed,
+        failed
+    );
+    if failed > 0 {
+        return Err(format!("{} images failed to import", failed));
+    }
+    Ok(())
+}
+
+fn image_url(image_id: &ImageId, host: &str) -> String {
+    format!("https://{}/{}", host, image_id)
+}
+
+/// Add the path to `files`, or every file inside it if it's a directory.
+///
+/// Symlinks to directories inside a directory are skipped, since they could
+/// point back up and make this loop forever. A symlink passed as the path
+/// itself is still followed.
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if path.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.and_then(|e| Ok((e.path(), e.file_type()?))))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (entry, file_type) in entries {
+            if file_type.is_symlink() && entry.is_dir() {
+                continue;
+            }
+            collect_files(&entry, files)?;
+        }
+    } else {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+/// Figure out the format of an image from the first bytes of the file.
+fn detect_format(path: &Path) -> Result<ImageFormat, String> {
+    ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| e.to_string())?
+        .format()
+        .ok_or_else(|| "Not an image".to_string())
+}
+
+/// The id for `--ids-from-filenames`, which is the filename without its
+/// extension. It has to follow the same rules as custom ids in uploads.
+fn image_id_from_filename(path: &Path) -> Result<ImageId, String> {
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    if !ids::is_valid_custom_id(stem) {
+        return Err(format!("{:?} can't be used as an image id", stem));
+    }
+    Ok(ImageId(stem.to_string()))
+}
+
+async fn import_file(
+    collections: &db::Collections,
+    config: &Config,
+    path: &Path,
+    format: ImageFormat,
+    id_from_filename: bool,
+) -> Result<ImageId, String> {
+    let image_id = if id_from_filename {
+        Some(image_id_from_filename(path)?)
+    } else {
+        None
+    };
+    upload_image_with_id(
+        path.to_path_buf(),
+        Some(format.to_mime_type().to_string()),
+        collections,
+        config,
+        image_id,
+        Visibility::Public,
+        None,
+    )
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Write the path and url of every imported file to a CSV file.
+fn write_csv(path: &Path, imported: &[ImportedFile], host: &str) -> Result<(), csv::Error> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["path", "id", "url", "error"])?;
+    for file in imported {
+        let path = file.path.to_string_lossy();
+        match &file.result {
+            Ok(image_id) => {
+                writer.write_record([&path, &*image_id.0, &image_url(image_id, host), ""])?
+            }
+            Err(e) => writer.write_record([&path, "", "", e])?,
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_from_ordinary_filenames() {
+        assert_eq!(
+            image_id_from_filename(Path::new("photos/cat.png"))
+                .unwrap()
+                .0,
+            "cat"
+        );
+        assert_eq!(
+            image_id_from_filename(Path::new("screenshot-1.webp"))
+                .unwrap()
+                .0,
+            "screenshot-1"
+        );
+        assert!(image_id_from_filename(Path::new("a.png")).is_err());
+        assert!(image_id_from_filename(Path::new("has space.png")).is_err());
+        assert!(image_id_from_filename(Path::new("api.png")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories_are_skipped() {
+        let dir =
+            std::env::temp_dir().join(format!("image-host-test-{}", util::generate_api_key()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/a.png"), b"").unwrap();
+        // a loop back to the top, and a link to a file
+        std::os::unix::fs::symlink(&dir, dir.join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.join("sub/a.png"), dir.join("b.png")).unwrap();
+
+        let mut files = Vec::new();
+        let result = collect_files(&dir, &mut files);
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+
+        assert_eq!(files, vec![dir.join("b.png"), dir.join("sub/a.png")]);
+    }
+}
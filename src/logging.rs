@@ -0,0 +1,133 @@
+//! Logging with `tracing`. Every request gets a span with an id, which is
+//! sent back in the `X-Request-Id` header, and background jobs get spans too,
+//! so everything logged for one upload or job can be picked out. Spans log how
+//! long they took when they close, which times each stage of an upload.
+//!
+//! Logs go to stderr as plain text unless `log.format` is `json`, and
+//! `RUST_LOG` picks what's logged.
+
+use crate::util;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Data, Request, Response};
+use tracing::{field, info_span, Span};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One json object per line, for log collectors
+    Json,
+}
+
+/// Start logging, with `default_level` used if `RUST_LOG` isn't set. This also
+/// picks up everything logged with `log`, like Rocket's own messages.
+pub fn init(default_level: &str, format: LogFormat) {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr);
+    let result = match format {
+        LogFormat::Json => subscriber.json().with_span_list(true).try_init(),
+        LogFormat::Text => subscriber.try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("Couldn't set up logging: {}", e);
+    }
+}
+
+/// Whether a request id sent by a client (or a proxy in front of us) is
+/// reasonable to put in our logs.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+}
+
+/// The span for a request, made by [`RequestTracing`]. Routes that do a lot of
+/// work, like uploads, take this as a request guard and run inside it.
+pub struct RequestSpan {
+    pub id: String,
+    pub span: Span,
+}
+
+impl RequestSpan {
+    fn none() -> RequestSpan {
+        RequestSpan {
+            id: String::new(),
+            span: Span::none(),
+        }
+    }
+}
+
+/// A fairing that gives every request a [`RequestSpan`].
+pub struct RequestTracing;
+
+#[rocket::async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        let id = req
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .filter(|id| is_valid_request_id(id))
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| util::generate_random_id(16).0);
+        let span = info_span!(
+            "request",
+            id = %id,
+            method = %req.method(),
+            uri = %req.uri(),
+            status = field::Empty,
+        );
+        req.local_cache(|| RequestSpan { id, span });
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let request_span = req.local_cache(RequestSpan::none);
+        request_span.span.record("status", res.status().code);
+        if !request_span.id.is_empty() {
+            res.set_raw_header(REQUEST_ID_HEADER, request_span.id.clone());
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r RequestSpan {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(req.local_cache(RequestSpan::none))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_ids_from_clients_are_checked() {
+        assert!(is_valid_request_id("3f2c1d9e-8a7b-4c6d-9e0f-1a2b3c4d5e6f"));
+        assert!(is_valid_request_id("abc_123"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("has spaces"));
+        assert!(!is_valid_request_id("new\nline"));
+        assert!(!is_valid_request_id(&"a".repeat(65)));
+    }
+}
@@ -4,33 +4,85 @@ extern crate rocket;
 #[macro_use]
 extern crate lazy_static;
 
+// see the comment on `mod moderation`
+#[allow(unused_imports, renamed_and_removed_lints)]
+mod admin;
+// rocket's route attributes re-export a uri macro for each route, which is
+// only used when the route is in the crate root, so modules with routes
+// allow unused imports
+#[allow(unused_imports)]
+mod albums;
+mod archive;
+mod auth;
 mod background_optimization;
+mod blocklist;
+mod cli;
+mod commands;
+mod config;
 mod db;
 mod encoding;
+mod error;
+#[allow(unused_imports)]
+mod health;
+mod ids;
+mod import;
+mod logging;
+#[allow(unused_imports)]
+mod metrics;
+// see the comment on `mod albums`, and deriving FromForm adds an allow for a
+// lint that newer versions of rust removed
+#[allow(unused_imports, renamed_and_removed_lints)]
+mod moderation;
+#[allow(unused_imports)]
+mod oembed;
+mod reencode;
+mod remote;
+#[allow(unused_imports)]
+mod similar;
+#[allow(unused_imports)]
+mod tus;
 mod util;
-
-use background_optimization::{optimize_image_and_update, optimize_images_from_database};
+#[allow(unused_imports)]
+mod viewer;
+#[allow(unused_imports)]
+mod visibility;
+
+use auth::ApiKey;
+use background_optimization::{
+    image_options_for_level, optimize_image_and_update, optimize_images_from_database,
+    thumbnail_options_for_level,
+};
+use base64::{engine::general_purpose, Engine};
+use clap::Parser;
+use cli::{ArchiveCommand, Cli, Command, KeysCommand};
+use config::Config;
 use dotenv::dotenv;
-use log::info;
-use rocket::serde::{json::Json, Serialize};
+use error::UploadError;
+use futures::future::join_all;
+use health::BackgroundTasks;
+use logging::{RequestSpan, RequestTracing};
+use remote::RemoteFetcher;
+use rocket::data::{Limits, ToByteUnit};
+use rocket::serde::{json::Json, Deserialize, Serialize};
 use rocket::{
     http::{ContentType, Header},
     response::Redirect,
-    Data, State,
+    Build, Data, Rocket, State,
 };
+use tus::TusStore;
 
+use rocket_dyn_templates::Template;
 use rocket_multipart_form_data::{
-    mime, MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
+    mime, MultipartFormData, MultipartFormDataError, MultipartFormDataField,
+    MultipartFormDataOptions, Repetition,
 };
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::{join, task};
+use tracing::{debug, error, field, info, info_span, instrument, Instrument, Span};
 use util::ImageId;
-
-lazy_static! {
-    // this is required for the /api/upload route to have the right url
-    static ref HOST: String = std::env::var("HOST").unwrap_or("i.matdoes.dev".to_string());
-}
+use visibility::{UrlSignature, Visibility};
 
 #[derive(Responder)]
 #[response(status = 200)]
@@ -48,126 +100,294 @@ fn index() -> HtmlResponder {
     }
 }
 
-/// Upload an image to the database from the Pathbuf and metadata.
-async fn upload_image(
+/// Upload an image to the database from the Pathbuf. The format is detected
+/// from the contents of the file, and if the client told us a content type
+/// then it has to match. The given id is used instead of generating a random
+/// one if there is one, and this fails with a conflict if it's already taken.
+/// The API key it was uploaded with is saved, so only that key can sign urls
+/// to it.
+#[instrument(
+    name = "upload",
+    skip_all,
+    fields(image_id = field::Empty, visibility = visibility.as_str())
+)]
+async fn upload_image_with_id(
     path: PathBuf,
-    content_type_string: String,
-    images_collection: &mongodb::Collection<mongodb::bson::Document>,
-) -> Result<ImageId, String> {
+    content_type: Option<String>,
+    collections: &db::Collections,
+    config: &Config,
+    image_id: Option<ImageId>,
+    visibility: Visibility,
+    api_key: Option<&ApiKey>,
+) -> Result<ImageId, UploadError> {
+    if image_id.is_some() && visibility != Visibility::Public {
+        return Err(UploadError::BadRequest(
+            "Unlisted and private images can't have custom ids".to_string(),
+        ));
+    }
+
+    let images_collection = &collections.images;
+    let format = async {
+        let format = encoding::detect_format(&path, content_type.as_deref()).await?;
+        // make sure we can decode it before doing anything expensive
+        encoding::check_dimensions(&path, format).await?;
+        Ok::<_, UploadError>(format)
+    }
+    .instrument(info_span!("parse"))
+    .await?;
+
+    let file_bytes = tokio::fs::read(&path).await.map_err(|e| e.to_string())?;
+    let original_sha256 = util::sha256_hex(&file_bytes);
+    blocklist::check_sha256(&collections.blocklist, &config.blocklist, &original_sha256).await?;
+
     let encoded_image_future = encoding::image_path_to_encoded(
         Box::new(path.clone()),
-        &content_type_string,
-        encoding::FromImageOptions::default(),
-    );
+        format,
+        image_options_for_level(0, &config.images),
+    )
+    .instrument(info_span!("image"));
     // we generate a low quality thumbnail alongside the image
     let encoded_thumbnail_future = encoding::image_path_to_encoded(
         Box::new(path),
-        &content_type_string,
-        encoding::FromImageOptions {
-            max_size: Some(128),
-            ..encoding::FromImageOptions::default()
-        },
-    );
-    let image_id_future = db::generate_image_id(images_collection);
-
-    info!("Finished making futures image, doing encoding!");
+        format,
+        thumbnail_options_for_level(0, &config.images),
+    )
+    .instrument(info_span!("thumbnail"));
 
     // encode the full image and thumbnail at the same time
-    // also figure out the image id while we're doing this
-    let (encoded_image_result, encoded_thumbnail_result, image_id_result) = join!(
-        encoded_image_future,
-        encoded_thumbnail_future,
-        image_id_future
-    );
-
-    info!("Finished join");
-
+    let (encoded_image_result, encoded_thumbnail_result) =
+        join!(encoded_image_future, encoded_thumbnail_future);
     let (encoded_image, encoded_thumbnail) = (encoded_image_result?, encoded_thumbnail_result?);
 
-    let image_id = match image_id_result {
-        Ok(image_id) => image_id,
-        Err(e) => return Err(e.to_string()),
-    };
-
-    info!("Inserting image into database");
-
-    let insert_result = db::insert_image(
-        images_collection,
-        &db::NewImage {
-            id: &image_id,
-
-            data: &encoded_image.data,
-            content_type: &encoded_image.content_type,
-
-            thumbnail_data: &encoded_thumbnail.data,
-            thumbnail_content_type: &encoded_thumbnail.content_type,
-
-            size: encoded_image.size,
-
-            optim_level: 0,
-        },
-    )
-    .await;
-    // db::insert_image(&images_collection.images, &image_id, &encoded_image.data).await;
-    if insert_result.is_err() {
-        return Err(insert_result.err().unwrap().to_string());
+    if let Some(perceptual_hash) = encoded_thumbnail.perceptual_hash {
+        blocklist::check_perceptual_hash(
+            &collections.blocklist,
+            &config.blocklist,
+            perceptual_hash,
+        )
+        .await?;
     }
 
-    info!("uploaded image {}", &image_id);
+    // the id is only picked when inserting, so if another upload takes it
+    // first we find out from the unique _id instead of overwriting its image
+    let mut collisions = ids::Collisions::new(&config.ids, visibility);
+    let (image_id, image_doc) = loop {
+        let candidate_id = match &image_id {
+            Some(image_id) => image_id.clone(),
+            None => ids::random_id(&config.ids, visibility),
+        };
+        let insert_result = db::insert_new_image(
+            images_collection,
+            &db::NewImage {
+                id: &candidate_id,
+
+                data: &encoded_image.data,
+                content_type: &encoded_image.content_type,
+
+                thumbnail_data: &encoded_thumbnail.data,
+                thumbnail_content_type: &encoded_thumbnail.content_type,
+                placeholder: encoded_thumbnail.placeholder.as_ref(),
+                perceptual_hash: encoded_thumbnail.perceptual_hash,
+
+                size: encoded_image.size,
+
+                optim_level: 0,
+            },
+            visibility,
+            &original_sha256,
+            api_key.map(|api_key| api_key.id.as_str()),
+        )
+        .instrument(info_span!("insert"))
+        .await;
+        match insert_result {
+            Ok(image_doc) => break (candidate_id, image_doc),
+            Err(e) if db::is_duplicate_key_error(&e) && image_id.is_none() => {
+                collisions.record(&candidate_id)
+            }
+            Err(e) if db::is_duplicate_key_error(&e) => {
+                return Err(UploadError::Conflict(format!(
+                    "The id {} is already taken",
+                    candidate_id
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    Span::current().record("image_id", image_id.0.as_str());
+
+    info!(format = ?format, "uploaded image");
+    metrics::UPLOADS
+        .with_label_values(&[&format!("{:?}", format).to_lowercase()])
+        .inc();
+    metrics::OPTIMIZATION_BACKLOG.inc();
 
     let owned_images_collection = images_collection.clone();
+    let owned_image_config = config.images.clone();
     // optimize the image more heavily in the background so we can serve it faster
-    task::spawn(async move {
-        // if it fails optimizing, we don't care
-        optimize_image_and_update(&owned_images_collection, &insert_result.unwrap().unwrap())
-            .await
-            .ok();
-        info!("optimized!")
-    });
+    task::spawn(
+        async move {
+            // if it fails optimizing, we don't care
+            optimize_image_and_update(&owned_images_collection, &image_doc, &owned_image_config)
+                .await
+                .ok();
+        }
+        .in_current_span(),
+    );
 
     Ok(image_id)
 }
 
-#[post("/", data = "<data>")]
-async fn upload_image_route(
+/// What was uploaded from a multipart form.
+struct FormUpload {
+    image_ids: Vec<ImageId>,
+    /// The album the images were put in, if one was made
+    album_id: Option<ImageId>,
+    visibility: Visibility,
+}
+
+/// Parse the multipart form from an upload request and upload every file in
+/// the `image` field. If the form has an `album` or `title` field, or
+/// `always_album` is set and there's more than one image, the images are put
+/// in a new album in the order they were sent. A single image can be given a
+/// custom id with the `id` field if there's an API key, and the `visibility`
+/// field applies to all of them.
+async fn upload_images_from_form(
     content_type: &ContentType,
     data: Data<'_>,
-    collections: &State<db::Collections>,
-) -> Result<Redirect, String> {
+    collections: &db::Collections,
+    config: &Config,
+    api_key: Option<&ApiKey>,
+    always_album: bool,
+) -> Result<FormUpload, UploadError> {
     let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        // command line tools don't always know the type of the file, so we
+        // also accept octet-stream and figure out the format ourselves
         MultipartFormDataField::file("image")
-            .content_type_by_string(Some(mime::IMAGE_STAR))
-            .unwrap(),
+            .content_type(Some(mime::IMAGE_STAR))
+            .content_type(Some(mime::APPLICATION_OCTET_STREAM))
+            .repetition(Repetition::infinite()),
+        MultipartFormDataField::text("album"),
+        MultipartFormDataField::text("title"),
+        MultipartFormDataField::text("id"),
+        MultipartFormDataField::text("visibility"),
     ]);
 
     let multipart_form_data = MultipartFormData::parse(content_type, data, options)
         .await
-        .unwrap();
-
-    let image = multipart_form_data.files.get("image"); // Use the get method to preserve file fields from moving out of the MultipartFormData instance in order to delete them automatically when the MultipartFormData instance is being dropped
-
-    if let Some(file_fields) = image {
-        let file_field = &file_fields[0];
-
-        let _content_type = &file_field.content_type;
-        let _file_name = &file_field.file_name;
-        let path = file_field.path.clone();
+        .map_err(|e| match e {
+            MultipartFormDataError::DataTypeError(_) => UploadError::UnsupportedMediaType(
+                "Uploads must be images or application/octet-stream".to_string(),
+            ),
+            MultipartFormDataError::DataTooLargeError(_) => {
+                UploadError::PayloadTooLarge("The image is too big".to_string())
+            }
+            e => UploadError::BadRequest(format!("Invalid form: {:?}", e)),
+        })?;
+
+    let file_fields = match multipart_form_data.files.get("image") {
+        Some(file_fields) if !file_fields.is_empty() => file_fields,
+        _ => return Err(UploadError::BadRequest("no image selected :(".to_string())),
+    };
+    let text_field = |name| {
+        multipart_form_data
+            .texts
+            .get(name)
+            .and_then(|fields| fields.first())
+            .map(|field| field.text.trim().to_string())
+            .filter(|text| !text.is_empty())
+    };
+    let title = text_field("title");
+    let album_requested = text_field("album").is_some() || title.is_some();
+    let custom_id = ids::custom_id(text_field("id").as_deref(), api_key)?;
+    if custom_id.is_some() && file_fields.len() > 1 {
+        return Err(UploadError::BadRequest(
+            "A custom id can only be used when uploading one image".to_string(),
+        ));
+    }
+    let visibility = Visibility::requested(
+        text_field("visibility").as_deref(),
+        api_key,
+        &config.signing,
+    )?;
+
+    // encode all of the images at the same time
+    let upload_results = join_all(file_fields.iter().map(|file_field| {
+        debug!(
+            content_type = ?file_field.content_type,
+            file_name = ?file_field.file_name,
+            "uploading file from form"
+        );
+
+        upload_image_with_id(
+            file_field.path.clone(),
+            file_field.content_type.as_ref().map(|t| t.to_string()),
+            collections,
+            config,
+            custom_id.clone(),
+            visibility,
+            api_key,
+        )
+    }))
+    .await;
 
-        println!("content type: {:?}", _content_type);
-        println!("file name: {:?}", _file_name);
-        println!("path: {:?}", path);
+    let mut image_ids = Vec::new();
+    let mut first_error = None;
+    for result in upload_results {
+        match result {
+            Ok(image_id) => image_ids.push(image_id),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = first_error {
+        // the upload should either work completely or not at all, so get rid
+        // of the images that did work
+        for image_id in &image_ids {
+            db::delete_image(&collections.images, image_id).await.ok();
+        }
+        return Err(e);
+    }
 
-        let content_type_string = match _content_type {
-            Some(t) => t.to_string(),
-            None => return Err("No mimetype".to_string()),
-        };
+    let album_id = if album_requested || (always_album && image_ids.len() > 1) {
+        Some(
+            db::create_album(
+                &collections.albums,
+                &config.ids,
+                visibility,
+                title.as_deref(),
+                &image_ids,
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
 
-        let image_id: ImageId =
-            upload_image(path, content_type_string, &collections.images).await?;
+    Ok(FormUpload {
+        image_ids,
+        album_id,
+        visibility,
+    })
+}
 
-        Ok(Redirect::to(uri!(view_image_route(image_id.to_string()))))
-    } else {
-        Err("no image selected :(".to_string())
+#[post("/", data = "<data>")]
+async fn upload_image_route(
+    content_type: &ContentType,
+    data: Data<'_>,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+    request_span: &RequestSpan,
+) -> Result<Redirect, UploadError> {
+    let upload = upload_images_from_form(content_type, data, collections, config, None, true)
+        .instrument(request_span.span.clone())
+        .await?;
+
+    match upload.album_id {
+        Some(album_id) => Ok(Redirect::to(format!("/a/{}", album_id))),
+        None => Ok(Redirect::to(uri!(view_image_route(
+            upload.image_ids[0].to_string()
+        )))),
     }
 }
 
@@ -178,49 +398,87 @@ struct ApiUploadResult {
     view: String,
 }
 
+impl ApiUploadResult {
+    /// The links to private images are signed, so they work for
+    /// `signing.url_ttl_hours`.
+    fn new(image_id: &ImageId, config: &Config, visibility: Visibility) -> ApiUploadResult {
+        let query = match visibility {
+            Visibility::Private => format!(
+                "?{}",
+                visibility::default_signed_query(&config.signing, &image_id.0)
+            ),
+            Visibility::Public | Visibility::Unlisted => String::new(),
+        };
+        ApiUploadResult {
+            hash: image_id.to_string(),
+            url: format!("https://{}/{}{}", config.host, image_id, query),
+            view: format!("https://{}/v/{}{}", config.host, image_id, query),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ApiAlbumResult {
+    id: String,
+    url: String,
+}
+
+/// The response from the multipart upload endpoints. Uploading one image
+/// without an album gives the same response as before albums existed.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ApiFormUploadResult {
+    Image(ApiUploadResult),
+    Images {
+        images: Vec<ApiUploadResult>,
+        album: Option<ApiAlbumResult>,
+    },
+}
+
+impl ApiFormUploadResult {
+    fn new(upload: FormUpload, config: &Config) -> Self {
+        if upload.image_ids.len() == 1 && upload.album_id.is_none() {
+            return ApiFormUploadResult::Image(ApiUploadResult::new(
+                &upload.image_ids[0],
+                config,
+                upload.visibility,
+            ));
+        }
+        ApiFormUploadResult::Images {
+            images: upload
+                .image_ids
+                .iter()
+                .map(|image_id| ApiUploadResult::new(image_id, config, upload.visibility))
+                .collect(),
+            album: upload.album_id.map(|album_id| ApiAlbumResult {
+                url: format!("https://{}/a/{}", config.host, album_id),
+                id: album_id.to_string(),
+            }),
+        }
+    }
+}
+
 #[post("/api/upload", data = "<data>")]
 async fn api_upload_image_route(
     content_type: &ContentType,
     data: Data<'_>,
     collections: &State<db::Collections>,
-) -> Result<Json<ApiUploadResult>, String> {
-    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
-        MultipartFormDataField::file("image")
-            .content_type_by_string(Some(mime::IMAGE_STAR))
-            .unwrap(),
-    ]);
-
-    let multipart_form_data = MultipartFormData::parse(content_type, data, options)
-        .await
-        .unwrap();
-
-    let image = multipart_form_data.files.get("image"); // Use the get method to preserve file fields from moving out of the MultipartFormData instance in order to delete them automatically when the MultipartFormData instance is being dropped
-
-    if let Some(file_fields) = image {
-        let file_field = &file_fields[0];
-
-        let _content_type = &file_field.content_type;
-        let _file_name = &file_field.file_name;
-        let path = file_field.path.clone();
-
-        let content_type_string = match _content_type {
-            Some(t) => t.to_string(),
-            None => return Err("No mimetype".to_string()),
-        };
-
-        let image_id: Rc<ImageId> =
-            Rc::new(upload_image(path, content_type_string, &collections.images).await?);
-
-        Ok(Json(ApiUploadResult {
-            hash: image_id.to_string(),
-            url: format!("https://{}/{}", *HOST, image_id),
-            view: format!("https://{}/{}", *HOST, image_id),
-        }))
+    config: &State<Config>,
+    api_key: Option<ApiKey>,
+    request_span: &RequestSpan,
+) -> Result<Json<ApiFormUploadResult>, UploadError> {
+    let upload = upload_images_from_form(
+        content_type,
+        data,
+        collections,
+        config,
+        api_key.as_ref(),
+        false,
+    )
+    .instrument(request_span.span.clone())
+    .await?;
 
-        // Ok(Redirect::to(uri!(view_image_route(image_id.to_string()))))
-    } else {
-        Err("no image selected :(".to_string())
-    }
+    Ok(Json(ApiFormUploadResult::new(upload, config)))
 }
 
 #[post("/api/upload/short", data = "<data>")]
@@ -228,44 +486,198 @@ async fn api_upload_image_route_short(
     content_type: &ContentType,
     data: Data<'_>,
     collections: &State<db::Collections>,
-) -> Result<Json<ApiUploadResult>, String> {
-    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
-        MultipartFormDataField::file("image")
-            .content_type_by_string(Some(mime::IMAGE_STAR))
-            .unwrap(),
-    ]);
+    config: &State<Config>,
+    api_key: Option<ApiKey>,
+    request_span: &RequestSpan,
+) -> Result<Json<ApiFormUploadResult>, UploadError> {
+    let upload = upload_images_from_form(
+        content_type,
+        data,
+        collections,
+        config,
+        api_key.as_ref(),
+        false,
+    )
+    .instrument(request_span.span.clone())
+    .await?;
 
-    let multipart_form_data = MultipartFormData::parse(content_type, data, options)
-        .await
-        .unwrap();
+    Ok(Json(ApiFormUploadResult::new(upload, config)))
+}
 
-    let image = multipart_form_data.files.get("image"); // Use the get method to preserve file fields from moving out of the MultipartFormData instance in order to delete them automatically when the MultipartFormData instance is being dropped
+#[derive(Deserialize)]
+struct UrlUpload {
+    url: String,
+    /// A custom id for the image, for uploaders with an API key
+    id: Option<String>,
+    /// `public` (the default), `unlisted` or `private`
+    visibility: Option<String>,
+}
 
-    if let Some(file_fields) = image {
-        let file_field = &file_fields[0];
+/// Download an image from a url and upload it.
+#[post("/api/upload/url", data = "<body>")]
+async fn api_upload_url_route(
+    body: Json<UrlUpload>,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+    fetcher: &State<RemoteFetcher>,
+    api_key: Option<ApiKey>,
+    request_span: &RequestSpan,
+) -> Result<Json<ApiUploadResult>, UploadError> {
+    let image_id = ids::custom_id(body.id.as_deref(), api_key.as_ref())?;
+    let visibility = Visibility::requested(
+        body.visibility.as_deref(),
+        api_key.as_ref(),
+        &config.signing,
+    )?;
+    let path = temp_upload_path();
+    let upload_result = async {
+        fetcher.fetch_to_file(&body.url, &path).await?;
+        // the content type the server sent could be anything, so we only go
+        // by what the file actually is
+        upload_image_with_id(
+            path.clone(),
+            None,
+            collections,
+            config,
+            image_id,
+            visibility,
+            api_key.as_ref(),
+        )
+        .await
+    }
+    .instrument(request_span.span.clone())
+    .await;
+    tokio::fs::remove_file(&path).await.ok();
 
-        let _content_type = &file_field.content_type;
-        let _file_name = &file_field.file_name;
-        let path = file_field.path.clone();
+    Ok(Json(ApiUploadResult::new(
+        &upload_result?,
+        config,
+        visibility,
+    )))
+}
 
-        let content_type_string = match _content_type {
-            Some(t) => t.to_string(),
-            None => return Err("No mimetype".to_string()),
-        };
+/// Upload the raw request body as an image, for `curl --data-binary` and
+/// clipboard tools. The format is detected from the body, and the content type
+/// is only checked against it. Uploaders with an API key can pick the id
+/// with `?id=`, and `?visibility=` works like in the other upload routes.
+#[put("/api/upload?<id>&<visibility>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn api_upload_raw_route(
+    id: Option<&str>,
+    visibility: Option<&str>,
+    content_type: Option<&ContentType>,
+    data: Data<'_>,
+    limits: &Limits,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+    api_key: Option<ApiKey>,
+    request_span: &RequestSpan,
+) -> Result<Json<ApiUploadResult>, UploadError> {
+    let image_id = ids::custom_id(id, api_key.as_ref())?;
+    let visibility = Visibility::requested(visibility, api_key.as_ref(), &config.signing)?;
+    let path = temp_upload_path();
+    let upload_result = async {
+        let limit = limits.get("file").unwrap_or(16.mebibytes());
+        let file = data
+            .open(limit)
+            .into_file(&path)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !file.is_complete() {
+            return Err(UploadError::PayloadTooLarge(format!(
+                "The image is bigger than {}",
+                limit
+            )));
+        }
+        // curl --data-binary sends application/x-www-form-urlencoded unless
+        // told otherwise, so only image content types are checked
+        let content_type = content_type
+            .filter(|t| t.top() == "image")
+            .map(|t| t.to_string());
+        upload_image_with_id(
+            path.clone(),
+            content_type,
+            collections,
+            config,
+            image_id,
+            visibility,
+            api_key.as_ref(),
+        )
+        .await
+    }
+    .instrument(request_span.span.clone())
+    .await;
+    tokio::fs::remove_file(&path).await.ok();
 
-        let image_id: Rc<ImageId> =
-            Rc::new(upload_image(path, content_type_string, &collections.images).await?);
+    Ok(Json(ApiUploadResult::new(
+        &upload_result?,
+        config,
+        visibility,
+    )))
+}
 
-        Ok(Json(ApiUploadResult {
-            hash: image_id.to_string(),
-            url: format!("https://{}/{}", *HOST, image_id),
-            view: format!("https://{}/{}", *HOST, image_id),
-        }))
+#[derive(Deserialize)]
+struct Base64Upload {
+    /// The image as base64, or as a data uri like `data:image/png;base64,...`
+    image: String,
+    /// A custom id for the image, for uploaders with an API key
+    id: Option<String>,
+    /// `public` (the default), `unlisted` or `private`
+    visibility: Option<String>,
+}
 
-        // Ok(Redirect::to(uri!(view_image_route(image_id.to_string()))))
-    } else {
-        Err("no image selected :(".to_string())
+/// Upload an image sent as base64 in a JSON body.
+#[post("/api/upload/base64", data = "<body>")]
+async fn api_upload_base64_route(
+    body: Json<Base64Upload>,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+    api_key: Option<ApiKey>,
+    request_span: &RequestSpan,
+) -> Result<Json<ApiUploadResult>, UploadError> {
+    let image_id = ids::custom_id(body.id.as_deref(), api_key.as_ref())?;
+    let visibility = Visibility::requested(
+        body.visibility.as_deref(),
+        api_key.as_ref(),
+        &config.signing,
+    )?;
+    let (content_type, image_bytes) =
+        util::decode_base64_image(&body.image).map_err(UploadError::BadRequest)?;
+
+    let path = temp_upload_path();
+    let upload_result = async {
+        tokio::fs::write(&path, image_bytes)
+            .await
+            .map_err(|e| e.to_string())?;
+        upload_image_with_id(
+            path.clone(),
+            content_type,
+            collections,
+            config,
+            image_id,
+            visibility,
+            api_key.as_ref(),
+        )
+        .await
     }
+    .instrument(request_span.span.clone())
+    .await;
+    tokio::fs::remove_file(&path).await.ok();
+
+    Ok(Json(ApiUploadResult::new(
+        &upload_result?,
+        config,
+        visibility,
+    )))
+}
+
+/// A path in the temp directory for writing an uploaded file to before it
+/// gets encoded. The caller is responsible for deleting it.
+fn temp_upload_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "image-host-upload-{}",
+        util::generate_random_id(16)
+    ))
 }
 
 #[derive(Responder)]
@@ -276,19 +688,23 @@ struct MyResponder {
     more: Header<'static>,
 }
 
-#[get("/<id>")]
+// browsers navigating to an image get the viewer page instead, see `viewer`
+#[get("/<id>", rank = 2)]
 async fn view_image_route(
     id: String,
+    signature: UrlSignature,
     images_collection: &State<db::Collections>,
-) -> Result<MyResponder, String> {
+) -> Result<MyResponder, UploadError> {
     let image_doc_option = match db::get_image(&images_collection.images, &id).await {
         Ok(image_doc) => image_doc,
-        Err(e) => return Err(e.to_string()),
+        Err(e) => return Err(e.into()),
     };
     let image_doc = match image_doc_option {
         Some(image_doc) => image_doc,
-        None => return Err("No image found".to_string()),
+        None => return Err(UploadError::NotFound("No image found".to_string())),
     };
+    moderation::check_not_disabled(&image_doc)?;
+    visibility::check_access(&image_doc, &signature)?;
 
     let image_data: Vec<u8> = image_doc.get_binary_generic("data").unwrap().clone();
     let content_type: String = image_doc.get_str("content_type").unwrap().to_string();
@@ -302,6 +718,7 @@ async fn view_image_route(
             .ok();
     });
 
+    metrics::VIEWS.with_label_values(&["image"]).inc();
     Ok(MyResponder {
         inner: image_data,
         more: Header::new("Content-Type", content_type),
@@ -309,13 +726,20 @@ async fn view_image_route(
 }
 
 // this is here for compatibility with the old version of the site
-#[get("/image/<id>")]
-async fn redirect_image_route(id: String) -> Redirect {
-    Redirect::to(uri!(view_image_route(id)))
+#[get("/image/<id>?<expires>&<sig>")]
+async fn redirect_image_route(id: String, expires: Option<i64>, sig: Option<&str>) -> Redirect {
+    let uri = uri!(view_image_route(id.as_str()));
+    // signed urls to private images have to keep working after the redirect
+    match (expires, sig) {
+        (Some(expires), Some(sig)) if sig.bytes().all(|c| c.is_ascii_hexdigit()) => {
+            Redirect::to(format!("{}?expires={}&sig={}", uri, expires, sig))
+        }
+        _ => Redirect::to(uri),
+    }
 }
 
 // the data returned from the /json/ route.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 struct DocumentJson {
     // these are identical, for compatibility
     pub _id: String,
@@ -332,76 +756,289 @@ struct DocumentJson {
 
     #[serde(rename = "thumbnail-content-type")]
     pub thumbnail_content_type: String,
+
+    /// A much smaller alternative to the thumbnail for showing while the
+    /// image loads. Images that haven't been re-encoded since these were
+    /// added don't have them.
+    pub blurhash: Option<String>,
+    #[serde(rename = "average-color")]
+    pub average_color: Option<String>,
+}
+
+impl DocumentJson {
+    fn from_doc(image_doc: &mongodb::bson::Document) -> Result<DocumentJson, String> {
+        let id = image_doc.get_str("_id").map_err(|e| e.to_string())?;
+        let content_type = image_doc
+            .get_str("content_type")
+            .map_err(|e| e.to_string())?;
+
+        let thumbnail_data = image_doc
+            .get_binary_generic("thumbnail_data")
+            .map_err(|e| e.to_string())?;
+        let thumbnail_content_type = image_doc
+            .get_str("thumbnail_content_type")
+            .map_err(|e| e.to_string())?;
+
+        Ok(DocumentJson {
+            _id: id.to_string(),
+            id: id.to_string(),
+            width: image_doc.get_i32("width").map_err(|e| e.to_string())? as u32,
+            height: image_doc.get_i32("height").map_err(|e| e.to_string())? as u32,
+            content_type: content_type.to_string(),
+            thumbnail_b64: general_purpose::STANDARD.encode(thumbnail_data),
+            thumbnail_content_type: thumbnail_content_type.to_string(),
+            blurhash: image_doc.get_str("blurhash").ok().map(|s| s.to_string()),
+            average_color: image_doc
+                .get_str("average_color")
+                .ok()
+                .map(|s| s.to_string()),
+        })
+    }
 }
 
 #[get("/json/<id>")]
 async fn get_image_json_route(
     id: String,
+    signature: UrlSignature,
     images_collection: &State<db::Collections>,
-) -> Result<Json<DocumentJson>, String> {
+) -> Result<Json<DocumentJson>, UploadError> {
     let image_doc_option = match db::get_image(&images_collection.images, &id).await {
         Ok(image_doc) => image_doc,
-        Err(e) => return Err(e.to_string()),
+        Err(e) => return Err(e.into()),
     };
     let image_doc = match image_doc_option {
         Some(image_doc) => image_doc,
-        None => return Err("No image found".to_string()),
+        None => return Err(UploadError::NotFound("No image found".to_string())),
     };
+    moderation::check_not_disabled(&image_doc)?;
+    visibility::check_access(&image_doc, &signature)?;
 
-    let image_data: Vec<u8> = image_doc.get_binary_generic("data").unwrap().clone();
-    let content_type: String = image_doc.get_str("content_type").unwrap().to_string();
-
-    let thumbnail_data: Vec<u8> = image_doc
-        .get_binary_generic("thumbnail_data")
-        .unwrap()
-        .clone();
-    let thumbnail_content_type: String = image_doc
-        .get_str("thumbnail_content_type")
-        .unwrap()
-        .to_string();
-
-    Ok(Json(DocumentJson {
-        _id: id.clone(),
-        id,
-        width: image_doc.get_i32("width").unwrap() as u32,
-        height: image_doc.get_i32("height").unwrap() as u32,
-        content_type,
-        thumbnail_b64: base64::encode(thumbnail_data),
-        thumbnail_content_type,
-    }))
+    metrics::VIEWS.with_label_values(&["json"]).inc();
+    Ok(Json(DocumentJson::from_doc(&image_doc)?))
 }
 
-#[launch]
-async fn rocket() -> _ {
-    info!("Starting server");
+fn rocket(collections: db::Collections, config: Config) -> Result<Rocket<Build>, String> {
+    metrics::init();
+    let tus_store = Arc::new(TusStore::new(&config.tus)?);
+    let remote_fetcher = RemoteFetcher::from_config(&config.url_upload)?;
+
+    let background_tasks = BackgroundTasks::default();
+    let owned_images_collection = collections.images.clone();
+    // similar images just won't be found until the next restart if this fails
+    background_tasks.spawn_once(
+        "load-similarity-index",
+        async move { similar::load_index(&owned_images_collection).await }
+            .instrument(info_span!("job", name = "load-similarity-index")),
+    );
+
+    let owned_collections = collections.clone();
+    let owned_image_config = config.images.clone();
+    background_tasks.spawn_once("background-optimization", async move {
+        optimize_images_from_database(&owned_collections, &owned_image_config).await
+    });
 
+    let owned_tus_store = tus_store.clone();
+    // delete abandoned resumable uploads every hour
+    background_tasks.spawn("tus-cleanup", async move {
+        loop {
+            let remove_result = owned_tus_store
+                .remove_expired()
+                .instrument(info_span!("job", name = "tus-cleanup"))
+                .await;
+            match remove_result {
+                Ok(removed) => info!(removed, "Removed expired uploads"),
+                Err(e) => error!(error = %e, "Error removing expired uploads"),
+            }
+            tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+        }
+    });
+
+    // rocket's messages go through our logging, which does its own formatting
+    Ok(
+        rocket::custom(config::figment().merge(("cli_colors", false)))
+            .manage(collections)
+            .manage(config)
+            .manage(remote_fetcher)
+            .manage(tus_store)
+            .manage(background_tasks)
+            .mount(
+                "/",
+                routes![
+                    index,
+                    upload_image_route,
+                    view_image_route,
+                    redirect_image_route,
+                    get_image_json_route,
+                    api_upload_image_route,
+                    api_upload_image_route_short,
+                    api_upload_url_route,
+                    api_upload_raw_route,
+                    api_upload_base64_route,
+                ],
+            )
+            .mount("/", tus::routes())
+            .mount("/", albums::routes())
+            .mount("/", viewer::routes())
+            .mount("/", oembed::routes())
+            .mount("/", similar::routes())
+            .mount("/", moderation::routes())
+            .mount("/", admin::routes())
+            .mount("/", metrics::routes())
+            .mount("/", health::routes())
+            .mount("/", visibility::routes())
+            .attach(Template::fairing())
+            .attach(RequestTracing),
+    )
+}
+
+#[rocket::main]
+async fn main() -> Result<(), String> {
     dotenv().ok();
 
-    let images_collection = db::connect().await.unwrap();
+    let cli = Cli::parse();
+    let config = config::load()?;
+    // the other commands print what they did, so only problems are logged
+    logging::init(
+        match cli.command {
+            None | Some(Command::Serve) => "info",
+            _ => "warn",
+        },
+        config.log.format,
+    );
+    encoding::configure(&config.decoding);
+
+    let collections = db::connect(&config.database).await?;
+
+    info!("Connected to database");
+
+    match cli.command {
+        None | Some(Command::Serve) => {
+            info!("Starting server");
+            rocket(collections, config)?
+                .launch()
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Some(Command::Import(args)) => import::import(&collections, &config, &args).await?,
+        Some(Command::Export { id, output }) => commands::export(&collections, &id, output).await?,
+        Some(Command::Archive(ArchiveCommand::Export { path })) => {
+            let exported = archive::export_archive(&collections, &path).await?;
+            println!("Exported {} images to {}", exported, path.display());
+        }
+        Some(Command::Archive(ArchiveCommand::Import { path })) => {
+            let stats = archive::import_archive(&collections, &config.blocklist, &path).await?;
+            println!(
+                "Imported {} images, skipped {} that already existed and {} on the blocklist, {} were incomplete",
+                stats.imported,
+                stats.conflicts.len(),
+                stats.blocked.len(),
+                stats.incomplete.len()
+            );
+        }
+        Some(Command::Delete { id }) => commands::delete(&collections, &id).await?,
+        Some(Command::Stats) => commands::stats(&collections).await?,
+        Some(Command::Gc) => commands::gc(&collections, &config).await?,
+        Some(Command::Keys(KeysCommand::Create { name, admin })) => {
+            commands::create_key(&collections, &name, admin).await?
+        }
+        Some(Command::Reencode(args)) => {
+            let stats = reencode::reencode_images(&collections, &args, &config.images).await?;
+            let (replaced, saved) = if args.dry_run {
+                ("would be replaced", "would save")
+            } else {
+                ("replaced", "saved")
+            };
+            println!(
+                "Re-encoded {} images ({} {}, {} kept, {} failed), {} {} bytes",
+                stats.scanned,
+                stats.replaced,
+                replaced,
+                stats.kept,
+                stats.failed,
+                saved,
+                stats.bytes_saved()
+            );
+        }
+        Some(Command::Similar { id, file, distance }) => {
+            commands::similar(
+                &collections,
+                &config,
+                id.as_deref(),
+                file.as_deref(),
+                distance,
+            )
+            .await?
+        }
+        Some(Command::Blocklist(command)) => commands::blocklist(&collections, command).await?,
+        Some(Command::BackfillDimensions { restart }) => {
+            commands::backfill_dimensions(&collections, restart).await?
+        }
+    }
 
-    println!("Connected to database");
+    Ok(())
+}
 
-    let collections = db::Collections {
-        images: images_collection,
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+
+    /// A client for the whole server, using the database at
+    /// `TEST_MONGODB_URI`.
+    async fn test_client() -> Client {
+        let (database, collections) = db::test_collections().await;
+        let config = Config {
+            database,
+            ..Config::default()
+        };
+        Client::tracked(rocket(collections, config).unwrap())
+            .await
+            .unwrap()
+    }
 
-    let owned_images_collection = collections.images.clone();
-    tokio::spawn(async move {
-        optimize_images_from_database(&owned_images_collection)
+    #[rocket::async_test]
+    async fn old_urls_keep_their_signature() {
+        let client = Client::tracked(rocket::build().mount("/", routes![redirect_image_route]))
             .await
-            .expect("Failed optimizing images");
-    });
+            .unwrap();
+        for (uri, location) in [
+            ("/image/abc", "/abc"),
+            ("/image/abc?expires=10&sig=ab01", "/abc?expires=10&sig=ab01"),
+            ("/image/abc?expires=10", "/abc"),
+            ("/image/abc?expires=10&sig=x%26y", "/abc"),
+        ] {
+            let response = client.get(uri).dispatch().await;
+            assert_eq!(
+                response.headers().get_one("Location"),
+                Some(location),
+                "{}",
+                uri
+            );
+        }
+    }
 
-    rocket::build().manage(collections).mount(
-        "/",
-        routes![
-            index,
-            upload_image_route,
-            view_image_route,
-            redirect_image_route,
-            get_image_json_route,
-            api_upload_image_route,
-            api_upload_image_route_short,
-        ],
-    )
+    #[rocket::async_test]
+    #[ignore = "needs a MongoDB server at TEST_MONGODB_URI"]
+    async fn missing_images_are_not_found() {
+        let client = test_client().await;
+        for uri in ["/doesnotexist", "/json/doesnotexist"] {
+            let response = client.get(uri).dispatch().await;
+            assert_eq!(response.status(), Status::NotFound, "{}", uri);
+        }
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a MongoDB server at TEST_MONGODB_URI"]
+    async fn failed_logins_are_unauthorized() {
+        let client = test_client().await;
+        let response = client
+            .post("/admin/login")
+            .header(ContentType::Form)
+            .body("key=not-a-key")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert!(response.cookies().get_private(auth::KEY_COOKIE).is_none());
+    }
 }
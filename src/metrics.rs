@@ -0,0 +1,134 @@
+//! Prometheus metrics, served in the text format at `/metrics`.
+
+use crate::error::UploadError;
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent};
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use rocket::http::ContentType;
+use rocket::Route;
+
+lazy_static! {
+    /// Uploaded images, by the format they were uploaded in.
+    pub static ref UPLOADS: IntCounterVec = register_int_counter_vec!(
+        "image_host_uploads_total",
+        "Images uploaded, by the format they were uploaded in",
+        &["format"]
+    )
+    .unwrap();
+    /// How long each encoder took in `from_image`.
+    pub static ref ENCODE_SECONDS: HistogramVec = register_histogram_vec!(
+        "image_host_encode_duration_seconds",
+        "How long encoding an image took, by encoder",
+        &["encoder"],
+        // 10ms to about 40 seconds
+        exponential_buckets(0.01, 2.0, 13).unwrap()
+    )
+    .unwrap();
+    /// How much smaller images got from being optimized in the background.
+    pub static ref OPTIMIZATION_BYTES_SAVED: IntCounter = register_int_counter!(
+        "image_host_optimization_bytes_saved_total",
+        "Bytes saved by optimizing images in the background"
+    )
+    .unwrap();
+    /// Images optimized in the background.
+    pub static ref OPTIMIZATIONS: IntCounter = register_int_counter!(
+        "image_host_optimizations_total",
+        "Images optimized in the background"
+    )
+    .unwrap();
+    /// Images served, by whether it was the image itself, the viewer page, or
+    /// its JSON.
+    pub static ref VIEWS: IntCounterVec = register_int_counter_vec!(
+        "image_host_views_total",
+        "Images served, by how they were served",
+        &["kind"]
+    )
+    .unwrap();
+    /// How long database commands took, by the name of the command.
+    pub static ref DATABASE_SECONDS: HistogramVec = register_histogram_vec!(
+        "image_host_database_duration_seconds",
+        "How long database commands took, by command and whether they succeeded",
+        &["command", "status"],
+        // half a millisecond to about 30 seconds
+        exponential_buckets(0.0005, 2.0, 17).unwrap()
+    )
+    .unwrap();
+    /// Images that haven't been optimized in the background yet. This is
+    /// counted when the background optimization starts, and kept up to date by
+    /// uploads, optimizations and deletions after that.
+    pub static ref OPTIMIZATION_BACKLOG: IntGauge = register_int_gauge!(
+        "image_host_optimization_backlog",
+        "Images waiting to be optimized in the background"
+    )
+    .unwrap();
+}
+
+/// Register every metric, so they're all in `/metrics` from the start instead
+/// of showing up the first time they're used.
+pub fn init() {
+    lazy_static::initialize(&UPLOADS);
+    lazy_static::initialize(&ENCODE_SECONDS);
+    lazy_static::initialize(&OPTIMIZATION_BYTES_SAVED);
+    lazy_static::initialize(&OPTIMIZATIONS);
+    lazy_static::initialize(&VIEWS);
+    lazy_static::initialize(&DATABASE_SECONDS);
+    lazy_static::initialize(&OPTIMIZATION_BACKLOG);
+}
+
+/// Records how long every command sent to MongoDB takes, so everything in
+/// `db` is timed without having to wrap each function.
+pub struct DatabaseMetrics;
+
+impl CommandEventHandler for DatabaseMetrics {
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        DATABASE_SECONDS
+            .with_label_values(&[&event.command_name, "success"])
+            .observe(event.duration.as_secs_f64());
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        DATABASE_SECONDS
+            .with_label_values(&[&event.command_name, "failure"])
+            .observe(event.duration.as_secs_f64());
+    }
+}
+
+/// Render every registered metric in the Prometheus text format.
+fn render() -> Result<String, String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| e.to_string())?;
+    String::from_utf8(buffer).map_err(|e| e.to_string())
+}
+
+#[get("/metrics")]
+async fn metrics_route() -> Result<(ContentType, String), UploadError> {
+    // prometheus wants text/plain with the version of the format
+    let content_type =
+        ContentType::new("text", "plain").with_params([("version", "0.0.4"), ("charset", "utf-8")]);
+    Ok((content_type, render()?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![metrics_route]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered() {
+        init();
+        UPLOADS.with_label_values(&["png"]).inc();
+        ENCODE_SECONDS.with_label_values(&["webp"]).observe(0.5);
+        let text = render().unwrap();
+        assert!(text.contains("image_host_uploads_total{format=\"png\"}"));
+        assert!(text
+            .contains("image_host_encode_duration_seconds_bucket{encoder=\"webp\",le=\"0.64\"}"));
+        assert!(text.contains("# TYPE image_host_optimization_backlog gauge"));
+    }
+}
@@ -0,0 +1,408 @@
+//! Reporting images and the API moderators use to deal with the reports.
+//!
+//! Anyone can report an image. Admin API keys can list the open reports and
+//! dismiss them, disable the image (it stops being served but is kept), or
+//! delete it. Everything moderators do is written to the audit log.
+
+use crate::auth::AdminKey;
+use crate::error::UploadError;
+use crate::util::ImageId;
+use crate::{blocklist, db, DocumentJson};
+use bson::Document;
+use rocket::form::{Form, FromForm};
+use rocket::request::FromParam;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Route, State};
+use std::collections::HashMap;
+
+const MAX_REASON_LENGTH: usize = 1000;
+const MAX_REPORTER_LENGTH: usize = 200;
+
+/// Make sure an image hasn't been disabled by a moderator before serving it.
+pub fn check_not_disabled(image_doc: &Document) -> Result<(), UploadError> {
+    if image_doc.get_bool("disabled").unwrap_or(false) {
+        return Err(UploadError::UnavailableForLegalReasons(
+            "This image has been disabled.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Check and save a report about an image.
+async fn create_report(
+    collections: &db::Collections,
+    image_id: &str,
+    reason: &str,
+    reporter: Option<&str>,
+) -> Result<String, UploadError> {
+    let reason = reason.trim();
+    let reporter = reporter.map(str::trim).filter(|r| !r.is_empty());
+    if reason.is_empty() {
+        return Err(UploadError::BadRequest("A reason is required".to_string()));
+    }
+    if reason.chars().count() > MAX_REASON_LENGTH
+        || reporter.is_some_and(|r| r.chars().count() > MAX_REPORTER_LENGTH)
+    {
+        return Err(UploadError::BadRequest(
+            "The report is too long".to_string(),
+        ));
+    }
+    if db::get_image_metadata(&collections.images, image_id)
+        .await?
+        .is_none()
+    {
+        return Err(UploadError::NotFound("No image found".to_string()));
+    }
+
+    Ok(db::insert_report(&collections.reports, image_id, reason, reporter).await?)
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NewReport {
+    image: String,
+    reason: String,
+    /// How to contact the person reporting, if they want to be contacted
+    reporter: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ReportCreated {
+    id: String,
+}
+
+#[post("/api/report", data = "<report>")]
+async fn api_report_route(
+    report: Json<NewReport>,
+    collections: &State<db::Collections>,
+) -> Result<Json<ReportCreated>, UploadError> {
+    let id = create_report(
+        collections,
+        &report.image,
+        &report.reason,
+        report.reporter.as_deref(),
+    )
+    .await?;
+    Ok(Json(ReportCreated { id }))
+}
+
+#[derive(FromForm)]
+struct ReportForm<'r> {
+    reason: &'r str,
+    reporter: Option<&'r str>,
+}
+
+/// The report form on the viewer page.
+#[post("/report/<id>", data = "<form>")]
+async fn report_form_route(
+    id: &str,
+    form: Form<ReportForm<'_>>,
+    collections: &State<db::Collections>,
+) -> Result<Redirect, UploadError> {
+    create_report(collections, id, form.reason, form.reporter).await?;
+    Ok(Redirect::to(format!("/v/{}?reported=1", id)))
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ReportJson {
+    id: String,
+    image: String,
+    reason: String,
+    reporter: Option<String>,
+    status: String,
+    /// When the report was made, in RFC 3339
+    date: Option<String>,
+    /// The reported image with its thumbnail, if it still exists
+    image_info: Option<DocumentJson>,
+}
+
+/// List reports, oldest first. Only the open ones are listed unless another
+/// status is given.
+#[get("/api/admin/reports?<status>&<limit>")]
+async fn list_reports_route(
+    status: Option<&str>,
+    limit: Option<i64>,
+    _admin: AdminKey,
+    collections: &State<db::Collections>,
+) -> Result<Json<Vec<ReportJson>>, UploadError> {
+    let report_docs = db::get_reports(
+        &collections.reports,
+        status.unwrap_or("open"),
+        limit.unwrap_or(100),
+    )
+    .await?;
+
+    let mut image_ids: Vec<String> = report_docs
+        .iter()
+        .filter_map(|r| r.get_str("image").ok().map(|id| id.to_string()))
+        .collect();
+    image_ids.sort();
+    image_ids.dedup();
+    let images: HashMap<String, DocumentJson> =
+        db::get_images_without_data(&collections.images, &image_ids)
+            .await?
+            .iter()
+            .filter_map(|image_doc| DocumentJson::from_doc(image_doc).ok())
+            .map(|image| (image.id.clone(), image))
+            .collect();
+
+    Ok(Json(
+        report_docs
+            .iter()
+            .map(|report_doc| {
+                let image = report_doc.get_str("image").unwrap_or_default();
+                ReportJson {
+                    id: report_doc.get_str("_id").unwrap_or_default().to_string(),
+                    image: image.to_string(),
+                    reason: report_doc.get_str("reason").unwrap_or_default().to_string(),
+                    reporter: report_doc.get_str("reporter").ok().map(|r| r.to_string()),
+                    status: report_doc.get_str("status").unwrap_or_default().to_string(),
+                    date: report_doc
+                        .get_datetime("date")
+                        .ok()
+                        .and_then(|date| date.try_to_rfc3339_string().ok()),
+                    image_info: images.get(image).cloned(),
+                }
+            })
+            .collect(),
+    ))
+}
+
+/// Something a moderator can do about an image.
+#[derive(Clone, Copy)]
+enum Action {
+    /// Close the reports without doing anything to the image
+    Dismiss,
+    /// Stop serving the image, but keep it
+    Disable,
+    /// Start serving a disabled image again
+    Enable,
+    Delete,
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Dismiss => "dismiss",
+            Action::Disable => "disable",
+            Action::Enable => "enable",
+            Action::Delete => "delete",
+        }
+    }
+
+    /// What the image's open reports are marked as after the action, if the
+    /// action resolves them
+    fn report_status(&self) -> Option<&'static str> {
+        match self {
+            Action::Dismiss => Some("dismissed"),
+            Action::Disable => Some("disabled"),
+            Action::Enable => None,
+            Action::Delete => Some("deleted"),
+        }
+    }
+}
+
+impl<'a> FromParam<'a> for Action {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        match param {
+            "dismiss" => Ok(Action::Dismiss),
+            "disable" => Ok(Action::Disable),
+            "enable" => Ok(Action::Enable),
+            "delete" => Ok(Action::Delete),
+            _ => Err(param),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ModerationResult {
+    image: String,
+    action: &'static str,
+    /// How many open reports about the image were closed
+    resolved_reports: u64,
+}
+
+/// Do something about an image, close its open reports, and write it to the
+/// audit log. If `block` is set when deleting, the image is also added to the
+/// blocklist.
+async fn moderate(
+    collections: &db::Collections,
+    moderator: &str,
+    image_id: &str,
+    action: Action,
+    report_id: Option<&str>,
+    block: bool,
+) -> Result<ModerationResult, UploadError> {
+    let image_exists = match action {
+        Action::Dismiss => true,
+        Action::Disable => db::set_image_disabled(&collections.images, image_id, true).await?,
+        Action::Enable => db::set_image_disabled(&collections.images, image_id, false).await?,
+        Action::Delete if block => {
+            blocklist::block_image(collections, &ImageId(image_id.to_string()), None)
+                .await
+                .map_err(UploadError::BadRequest)?;
+            true
+        }
+        Action::Delete => {
+            db::delete_image(&collections.images, &ImageId(image_id.to_string())).await?
+        }
+    };
+    if !image_exists {
+        return Err(UploadError::NotFound("No image found".to_string()));
+    }
+
+    let resolved_reports = match action.report_status() {
+        Some(status) => {
+            db::resolve_reports(&collections.reports, image_id, status, moderator).await?
+        }
+        None => 0,
+    };
+    let action_name = match action {
+        Action::Delete if block => "delete-and-block",
+        _ => action.name(),
+    };
+    db::insert_audit_log_entry(
+        &collections.audit_log,
+        moderator,
+        action_name,
+        image_id,
+        report_id,
+    )
+    .await?;
+
+    Ok(ModerationResult {
+        image: image_id.to_string(),
+        action: action_name,
+        resolved_reports,
+    })
+}
+
+/// Act on the image that a report is about. This closes every open report
+/// about the image, not just this one.
+#[post("/api/admin/reports/<id>/<action>?<block>")]
+async fn report_action_route(
+    id: &str,
+    action: Action,
+    block: Option<bool>,
+    admin: AdminKey,
+    collections: &State<db::Collections>,
+) -> Result<Json<ModerationResult>, UploadError> {
+    let report_doc = db::get_report(&collections.reports, id)
+        .await?
+        .ok_or_else(|| UploadError::NotFound("No report found".to_string()))?;
+    let image_id = report_doc.get_str("image").map_err(|e| e.to_string())?;
+    let result = moderate(
+        collections,
+        &admin.0.name,
+        image_id,
+        action,
+        Some(id),
+        block.unwrap_or(false),
+    )
+    .await?;
+    Ok(Json(result))
+}
+
+/// Act on an image directly, whether or not it was reported.
+#[post("/api/admin/images/<id>/<action>?<block>")]
+async fn image_action_route(
+    id: &str,
+    action: Action,
+    block: Option<bool>,
+    admin: AdminKey,
+    collections: &State<db::Collections>,
+) -> Result<Json<ModerationResult>, UploadError> {
+    let result = moderate(
+        collections,
+        &admin.0.name,
+        id,
+        action,
+        None,
+        block.unwrap_or(false),
+    )
+    .await?;
+    Ok(Json(result))
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AuditLogEntry {
+    /// In RFC 3339
+    date: Option<String>,
+    moderator: String,
+    action: String,
+    image: String,
+    report: Option<String>,
+}
+
+/// The most recent moderator actions, newest first.
+#[get("/api/admin/audit-log?<limit>")]
+async fn audit_log_route(
+    limit: Option<i64>,
+    _admin: AdminKey,
+    collections: &State<db::Collections>,
+) -> Result<Json<Vec<AuditLogEntry>>, UploadError> {
+    let entries = db::get_audit_log(&collections.audit_log, limit.unwrap_or(100)).await?;
+    Ok(Json(
+        entries
+            .iter()
+            .map(|entry| AuditLogEntry {
+                date: entry
+                    .get_datetime("date")
+                    .ok()
+                    .and_then(|date| date.try_to_rfc3339_string().ok()),
+                moderator: entry.get_str("moderator").unwrap_or_default().to_string(),
+                action: entry.get_str("action").unwrap_or_default().to_string(),
+                image: entry.get_str("image").unwrap_or_default().to_string(),
+                report: entry.get_str("report").ok().map(|r| r.to_string()),
+            })
+            .collect(),
+    ))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        api_report_route,
+        report_form_route,
+        list_reports_route,
+        report_action_route,
+        image_action_route,
+        audit_log_route
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    #[test]
+    fn disabled_images_are_unavailable() {
+        assert!(check_not_disabled(&doc! {"_id": "abcde"}).is_ok());
+        assert!(check_not_disabled(&doc! {"_id": "abcde", "disabled": false}).is_ok());
+        let error = check_not_disabled(&doc! {"_id": "abcde", "disabled": true}).unwrap_err();
+        assert_eq!(error.status().code, 451);
+    }
+
+    #[test]
+    fn only_deleting_and_disabling_resolve_reports() {
+        for name in ["dismiss", "disable", "enable", "delete"] {
+            let action = Action::from_param(name).unwrap();
+            assert_eq!(action.name(), name);
+            assert_eq!(
+                action.report_status().is_some(),
+                name != "enable",
+                "{}",
+                name
+            );
+        }
+        assert!(Action::from_param("ban").is_err());
+    }
+}
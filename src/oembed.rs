@@ -0,0 +1,209 @@
+//! An [oEmbed](https://oembed.com) provider, so sites that don't read
+//! OpenGraph metadata can still show a preview of an image.
+
+use crate::config::Config;
+use crate::visibility::Visibility;
+use crate::{db, moderation};
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{Route, State};
+use url::Url;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct OEmbed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    url: String,
+    width: u32,
+    height: u32,
+    provider_name: String,
+    provider_url: String,
+}
+
+impl OEmbed {
+    fn to_xml(&self) -> String {
+        let fields = [
+            ("version", self.version.to_string()),
+            ("type", self.kind.to_string()),
+            ("title", self.title.clone()),
+            ("url", self.url.clone()),
+            ("width", self.width.to_string()),
+            ("height", self.height.to_string()),
+            ("provider_name", self.provider_name.clone()),
+            ("provider_url", self.provider_url.clone()),
+        ];
+        let mut xml = String::from(
+            "<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>\n<oembed>\n",
+        );
+        for (name, value) in fields {
+            xml.push_str(&format!("\t<{name}>{}</{name}>\n", escape_xml(&value)));
+        }
+        xml.push_str("</oembed>\n");
+        xml
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[derive(Responder)]
+enum OEmbedResponse {
+    Json(Json<OEmbed>),
+    Xml((ContentType, String)),
+}
+
+/// Get the image id from a url to one of our image or viewer pages, like
+/// `https://i.matdoes.dev/abcde` or `https://i.matdoes.dev/v/abcde`.
+fn id_from_url(url: &str, host: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    if url.host_str()? != host {
+        return None;
+    }
+    let segments: Vec<&str> = url.path_segments()?.collect();
+    match segments.as_slice() {
+        [id] | ["v", id] | ["image", id] if !id.is_empty() => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// Scale the size down to fit in `maxwidth` and `maxheight`, keeping the aspect
+/// ratio. It's never scaled up.
+fn fit_within(
+    width: u32,
+    height: u32,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+) -> (u32, u32) {
+    let scale = [
+        maxwidth.map(|max| max as f64 / width as f64),
+        maxheight.map(|max| max as f64 / height as f64),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(1.0, f64::min);
+    if scale >= 1.0 {
+        return (width, height);
+    }
+    (
+        ((width as f64 * scale) as u32).max(1),
+        ((height as f64 * scale) as u32).max(1),
+    )
+}
+
+#[get("/oembed?<url>&<maxwidth>&<maxheight>&<format>")]
+async fn oembed_route(
+    url: &str,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+    format: Option<&str>,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+) -> Result<OEmbedResponse, Status> {
+    let format = format.unwrap_or("json");
+    if format != "json" && format != "xml" {
+        return Err(Status::NotImplemented);
+    }
+
+    let id = id_from_url(url, &config.host).ok_or(Status::NotFound)?;
+    let image_doc = db::get_image_metadata(&collections.images, &id)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::NotFound)?;
+    moderation::check_not_disabled(&image_doc).map_err(|e| e.status())?;
+    // the url we'd give back wouldn't be signed
+    if Visibility::from_doc(&image_doc) == Visibility::Private {
+        return Err(Status::NotFound);
+    }
+    let width = image_doc
+        .get_i32("width")
+        .map_err(|_| Status::InternalServerError)? as u32;
+    let height = image_doc
+        .get_i32("height")
+        .map_err(|_| Status::InternalServerError)? as u32;
+
+    // the image is only ever served at its full size, but consumers will
+    // display it at the size we give them
+    let (width, height) = fit_within(width, height, maxwidth, maxheight);
+
+    let oembed = OEmbed {
+        version: "1.0",
+        kind: "photo",
+        title: id.clone(),
+        url: format!("https://{}/{}?raw", config.host, id),
+        width,
+        height,
+        provider_name: config.host.clone(),
+        provider_url: format!("https://{}", config.host),
+    };
+
+    Ok(match format {
+        "xml" => OEmbedResponse::Xml((ContentType::XML, oembed.to_xml())),
+        _ => OEmbedResponse::Json(Json(oembed)),
+    })
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![oembed_route]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_from_image_and_viewer_urls() {
+        let host = "i.matdoes.dev";
+        assert_eq!(
+            id_from_url("https://i.matdoes.dev/abcde", host),
+            Some("abcde".to_string())
+        );
+        assert_eq!(
+            id_from_url("https://i.matdoes.dev/v/abcde?raw", host),
+            Some("abcde".to_string())
+        );
+        assert_eq!(
+            id_from_url("https://i.matdoes.dev/image/abcde", host),
+            Some("abcde".to_string())
+        );
+        assert_eq!(id_from_url("https://example.com/abcde", host), None);
+        assert_eq!(id_from_url("https://i.matdoes.dev/", host), None);
+        assert_eq!(id_from_url("https://i.matdoes.dev/a/b/c", host), None);
+        assert_eq!(id_from_url("not a url", host), None);
+    }
+
+    #[test]
+    fn sizes_fit_within_both_limits() {
+        // a wide image is limited by its width, even though maxheight is smaller
+        assert_eq!(fit_within(2000, 500, Some(400), Some(300)), (400, 100));
+        assert_eq!(fit_within(500, 2000, Some(300), Some(400)), (100, 400));
+        assert_eq!(fit_within(2000, 500, None, Some(100)), (400, 100));
+        assert_eq!(fit_within(2000, 500, Some(4000), Some(4000)), (2000, 500));
+        assert_eq!(fit_within(2000, 500, None, None), (2000, 500));
+    }
+
+    #[test]
+    fn xml_is_escaped() {
+        let oembed = OEmbed {
+            version: "1.0",
+            kind: "photo",
+            title: "<a&b>".to_string(),
+            url: "https://example.com/a?raw".to_string(),
+            width: 1,
+            height: 2,
+            provider_name: "example.com".to_string(),
+            provider_url: "https://example.com".to_string(),
+        };
+        let xml = oembed.to_xml();
+        assert!(xml.contains("<title>&lt;a&amp;b&gt;</title>"));
+        assert!(xml.contains("<width>1</width>"));
+    }
+}
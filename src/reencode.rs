@@ -0,0 +1,421 @@
+//! Re-encode the images that are already in the database, for when the
+//! encoder settings change.
+
+use crate::background_optimization::{
+    decode_image_doc, image_options_for_level, thumbnail_options_for_level,
+};
+use crate::cli::ReencodeArgs;
+use crate::config::ImageConfig;
+use crate::encoding::{from_image, EncodeResult};
+use crate::{db, util};
+use bson::{Bson, Document};
+use futures::future::join_all;
+use futures::join;
+use mongodb::Collection;
+use tracing::instrument;
+use util::ImageId;
+
+/// The name of the job in the jobs collection, used for resuming. Runs with a
+/// filter add it to the name, see [`job_name`].
+const JOB_NAME: &str = "reencode";
+
+#[derive(Debug, Default)]
+pub struct ReencodeStats {
+    /// How many images were looked at
+    pub scanned: u64,
+    /// How many images were replaced with the new encoding
+    pub replaced: u64,
+    /// How many images were kept because the new encoding wasn't smaller
+    pub kept: u64,
+    pub failed: u64,
+    /// The size of the replaced images before re-encoding, including thumbnails
+    pub bytes_before: u64,
+    /// The size of the replaced images after re-encoding, including thumbnails
+    pub bytes_after: u64,
+}
+
+impl ReencodeStats {
+    pub fn bytes_saved(&self) -> i64 {
+        self.bytes_before as i64 - self.bytes_after as i64
+    }
+}
+
+pub enum ReencodeOutcome {
+    Replaced { before: u64, after: u64 },
+    Kept,
+}
+
+/// The name a run with this filter saves its progress under. The checkpoint is
+/// the last id the run got to, which means nothing to a run with a different
+/// filter, so each filter gets its own.
+fn job_name(filter: &Document) -> String {
+    if filter.is_empty() {
+        return JOB_NAME.to_string();
+    }
+    let filter_json = Bson::Document(filter.clone()).into_relaxed_extjson();
+    format!("{}:{}", JOB_NAME, filter_json)
+}
+
+/// Re-encode every image matching the filter, saving progress after every
+/// batch so an interrupted run can pick up where it left off.
+pub async fn reencode_images(
+    collections: &db::Collections,
+    args: &ReencodeArgs,
+    config: &ImageConfig,
+) -> Result<ReencodeStats, String> {
+    let filter: Document = match &args.filter {
+        Some(filter) => serde_json::from_str(filter).map_err(|e| format!("Invalid filter: {e}"))?,
+        None => Document::new(),
+    };
+    let concurrency = args.concurrency.max(1);
+    let job = job_name(&filter);
+
+    // a dry run doesn't change anything, including the progress of a real run
+    let mut last_id = if args.dry_run {
+        None
+    } else if args.restart {
+        db::clear_job_checkpoint(&collections.jobs, &job)
+            .await
+            .map_err(|e| e.to_string())?;
+        None
+    } else {
+        db::get_job_checkpoint(&collections.jobs, &job)
+            .await
+            .map_err(|e| e.to_string())?
+    };
+    if let Some(last_id) = &last_id {
+        println!("Resuming after {}", last_id);
+    }
+
+    let mut stats = ReencodeStats::default();
+    loop {
+        let ids = db::get_image_ids_after(
+            &collections.images,
+            filter.clone(),
+            last_id.as_ref(),
+            concurrency as i64,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+        if ids.is_empty() {
+            break;
+        }
+
+        let results =
+            join_all(ids.iter().map(|id| {
+                reencode_image(&collections.images, id, args.force, args.dry_run, config)
+            }))
+            .await;
+        for (id, result) in ids.iter().zip(results) {
+            stats.scanned += 1;
+            match result {
+                Ok(ReencodeOutcome::Replaced { before, after }) => {
+                    println!("{}: {} -> {} bytes", id, before, after);
+                    stats.replaced += 1;
+                    stats.bytes_before += before;
+                    stats.bytes_after += after;
+                }
+                Ok(ReencodeOutcome::Kept) => stats.kept += 1,
+                Err(e) => {
+                    println!("{}: error re-encoding: {}", id, e);
+                    stats.failed += 1;
+                }
+            }
+        }
+
+        let batch_last_id = ids.last().unwrap().clone();
+        if !args.dry_run {
+            db::set_job_checkpoint(&collections.jobs, &job, &batch_last_id)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        last_id = Some(batch_last_id);
+    }
+
+    // we got through everything, so the next run should start over
+    if !args.dry_run {
+        db::clear_job_checkpoint(&collections.jobs, &job)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(stats)
+}
+
+/// Re-encode a single image from the stored data, which is the best copy of
+/// the image that we have.
+#[instrument(name = "reencode", skip_all, fields(image_id = %image_id, force, dry_run))]
+pub async fn reencode_image(
+    images_collection: &Collection<Document>,
+    image_id: &ImageId,
+    force: bool,
+    dry_run: bool,
+    config: &ImageConfig,
+) -> Result<ReencodeOutcome, String> {
+    let image_doc = db::get_image(images_collection, &image_id.0)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("Image was deleted")?;
+    let optim_level = image_doc.get_i32("optim_level").unwrap_or(0) as u8;
+    let before = (image_doc
+        .get_binary_generic("data")
+        .map_err(|e| e.to_string())?
+        .len()
+        + image_doc
+            .get_binary_generic("thumbnail_data")
+            .map_err(|e| e.to_string())?
+            .len()) as u64;
+
+    let (encoded_image, encoded_thumbnail) = encode_image_doc(&image_doc, config).await?;
+    let after = (encoded_image.data.len() + encoded_thumbnail.data.len()) as u64;
+
+    if after >= before && !force {
+        return Ok(ReencodeOutcome::Kept);
+    }
+    if dry_run {
+        return Ok(ReencodeOutcome::Replaced { before, after });
+    }
+
+    let updated = db::update_image(
+        images_collection,
+        &db::NewImage {
+            id: image_id,
+
+            data: &encoded_image.data,
+            content_type: &encoded_image.content_type,
+
+            thumbnail_data: &encoded_thumbnail.data,
+            thumbnail_content_type: &encoded_thumbnail.content_type,
+            placeholder: encoded_thumbnail.placeholder.as_ref(),
+            perceptual_hash: encoded_thumbnail.perceptual_hash,
+
+            size: encoded_image.size,
+
+            optim_level,
+        },
+        optim_level,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    if !updated {
+        return Err("Image was deleted or optimized while re-encoding".to_string());
+    }
+
+    Ok(ReencodeOutcome::Replaced { before, after })
+}
+
+/// Encode the image and thumbnail in an image document again, with the
+/// options for the optimization level it's already at.
+async fn encode_image_doc(
+    image_doc: &Document,
+    config: &ImageConfig,
+) -> Result<(EncodeResult, EncodeResult), String> {
+    let optim_level = image_doc.get_i32("optim_level").unwrap_or(0) as u8;
+    let image = decode_image_doc(image_doc).await?;
+    let (encoded_image_result, encoded_thumbnail_result) = join!(
+        from_image(image.clone(), image_options_for_level(optim_level, config)),
+        from_image(image, thumbnail_options_for_level(optim_level, config))
+    );
+    Ok((encoded_image_result?, encoded_thumbnail_result?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::visibility::Visibility;
+    use bson::{doc, spec::BinarySubtype, Binary};
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image =
+            DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, [200, 40, 40].into()));
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, ImageOutputFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    fn image_doc(data: Vec<u8>, optim_level: i32) -> Document {
+        doc! {
+            "data": Binary { subtype: BinarySubtype::Generic, bytes: data.clone() },
+            "content_type": "image/png",
+            "thumbnail_data": Binary { subtype: BinarySubtype::Generic, bytes: data },
+            "thumbnail_content_type": "image/png",
+            "optim_level": optim_level,
+        }
+    }
+
+    /// Store an image, with the given data as both the image and thumbnail.
+    async fn insert(
+        images: &Collection<Document>,
+        id: &ImageId,
+        data: Vec<u8>,
+        content_type: &str,
+    ) {
+        db::delete_image(images, id).await.unwrap();
+        db::insert_new_image(
+            images,
+            &db::NewImage {
+                id,
+                data: &data,
+                content_type,
+                thumbnail_data: &data,
+                thumbnail_content_type: content_type,
+                placeholder: None,
+                perceptual_hash: None,
+                size: (64, 64),
+                optim_level: 0,
+            },
+            Visibility::Public,
+            &util::sha256_hex(&data),
+            None,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn checkpoints_are_kept_per_filter() {
+        let name = |filter: &str| job_name(&serde_json::from_str(filter).unwrap());
+        assert_eq!(name("{}"), "reencode");
+        assert_eq!(
+            name(r#"{"content_type": "image/png"}"#),
+            name(r#"{ "content_type":"image/png" }"#)
+        );
+        assert_ne!(
+            name(r#"{"content_type": "image/png"}"#),
+            name(r#"{"content_type": "image/webp"}"#)
+        );
+        assert_ne!(name(r#"{"content_type": "image/png"}"#), name("{}"));
+    }
+
+    #[tokio::test]
+    async fn images_keep_their_optimization_level() {
+        let config = ImageConfig::default();
+
+        let (image, thumbnail) = encode_image_doc(&image_doc(png(2048, 64), 0), &config)
+            .await
+            .unwrap();
+        assert_eq!(image.size, (2048, 64));
+        assert_eq!(thumbnail.size, (128, 4));
+
+        // optimized images are shrunk again, like the background optimization
+        let (image, thumbnail) = encode_image_doc(&image_doc(png(2048, 64), 1), &config)
+            .await
+            .unwrap();
+        assert_eq!(image.size, (1024, 32));
+        assert_eq!(thumbnail.size, (128, 4));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server at TEST_MONGODB_URI"]
+    async fn only_smaller_images_are_replaced_unless_forced() {
+        let (_, collections) = db::test_collections().await;
+        let config = ImageConfig::default();
+        let image_id = ImageId("reencode-test-force".to_string());
+
+        // an image that's already encoded with the current settings doesn't
+        // get any smaller
+        let (encoded, _) = encode_image_doc(&image_doc(png(64, 64), 0), &config)
+            .await
+            .unwrap();
+        insert(
+            &collections.images,
+            &image_id,
+            encoded.data,
+            &encoded.content_type,
+        )
+        .await;
+        assert!(matches!(
+            reencode_image(&collections.images, &image_id, false, false, &config).await,
+            Ok(ReencodeOutcome::Kept)
+        ));
+        assert!(matches!(
+            reencode_image(&collections.images, &image_id, true, false, &config).await,
+            Ok(ReencodeOutcome::Replaced { .. })
+        ));
+
+        // an uncompressed png does, but a dry run leaves it alone
+        let original = png(64, 64);
+        insert(
+            &collections.images,
+            &image_id,
+            original.clone(),
+            "image/png",
+        )
+        .await;
+        assert!(matches!(
+            reencode_image(&collections.images, &image_id, false, true, &config).await,
+            Ok(ReencodeOutcome::Replaced { .. })
+        ));
+        let stored = db::get_image(&collections.images, &image_id.0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.get_binary_generic("data").unwrap(), &original);
+
+        assert!(matches!(
+            reencode_image(&collections.images, &image_id, false, false, &config).await,
+            Ok(ReencodeOutcome::Replaced { .. })
+        ));
+        let stored = db::get_image(&collections.images, &image_id.0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(stored.get_binary_generic("data").unwrap(), &original);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server at TEST_MONGODB_URI"]
+    async fn interrupted_runs_are_resumed() {
+        let (_, collections) = db::test_collections().await;
+        let ids = ["reencode-test-a", "reencode-test-b", "reencode-test-c"];
+        for id in ids {
+            insert(
+                &collections.images,
+                &ImageId(id.to_string()),
+                png(64, 64),
+                "image/png",
+            )
+            .await;
+        }
+        let filter = format!(r#"{{"_id": {{"$in": {:?}}}}}"#, ids);
+        let args = |filter: &str| ReencodeArgs {
+            filter: Some(filter.to_string()),
+            concurrency: 1,
+            restart: false,
+            force: false,
+            dry_run: false,
+        };
+        // as if the last run stopped after the second image
+        let job = job_name(&serde_json::from_str(&filter).unwrap());
+        db::set_job_checkpoint(&collections.jobs, &job, &ImageId(ids[1].to_string()))
+            .await
+            .unwrap();
+
+        // a run with a different filter doesn't use that progress
+        let other_filter = format!(
+            r#"{{"_id": {{"$in": {:?}}}, "content_type": "image/png"}}"#,
+            ids
+        );
+        let stats = reencode_images(&collections, &args(&other_filter), &ImageConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(stats.scanned, 3);
+
+        // but one with the same filter, written differently, does
+        let stats = reencode_images(
+            &collections,
+            &args(&filter.replace(' ', "")),
+            &ImageConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.scanned, 1);
+        // and the next run starts over
+        assert!(db::get_job_checkpoint(&collections.jobs, &job)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,373 @@
+//! Download images from urls so they can be rehosted. Since the server makes
+//! the requests, urls that point at private or loopback addresses are blocked
+//! unless they're in the allowlist, so people can't use us to reach internal
+//! services.
+
+use crate::config::UrlUploadConfig;
+use crate::error::UploadError;
+use futures::stream::StreamExt;
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{redirect, Url};
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// The most redirects we'll follow before giving up
+const MAX_REDIRECTS: usize = 5;
+
+/// Which addresses we're allowed to make requests to.
+pub struct AddressPolicy {
+    /// Networks that are allowed even though they're private
+    pub allowlist: Vec<IpNet>,
+}
+
+impl AddressPolicy {
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allowlist.iter().any(|net| net.contains(&ip)) || !is_private_ip(ip)
+    }
+
+    /// Check the host of a url if it's an ip address. Hostnames are checked
+    /// when they're resolved.
+    fn check_url(&self, url: &Url) -> Result<(), BlockedAddressError> {
+        let ip = match url.host() {
+            Some(url::Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(url::Host::Ipv6(ip)) => IpAddr::V6(ip),
+            _ => return Ok(()),
+        };
+        if self.is_allowed(ip) {
+            Ok(())
+        } else {
+            Err(BlockedAddressError(ip))
+        }
+    }
+}
+
+/// Whether the address is somewhere a public url shouldn't be pointing, like
+/// localhost or a private network.
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // shared address space (carrier-grade nat)
+                || (a == 100 && (64..128).contains(&b))
+                // benchmarking
+                || (a == 198 && (b & 0xfe) == 18)
+                // reserved
+                || a >= 240
+                || a == 0
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_private_ip(IpAddr::V4(ip));
+            }
+            // addresses with an ipv4 address in them get to that address, so
+            // it's the one that's checked
+            let segments = ip.segments();
+            let ipv4 = |high: u16, low: u16| IpAddr::V4(((high as u32) << 16 | low as u32).into());
+            match segments {
+                // nat64
+                [0x64, 0xff9b, 0, 0, 0, 0, high, low] => return is_private_ip(ipv4(high, low)),
+                // ipv4-compatible, which :: and ::1 also look like
+                [0, 0, 0, 0, 0, 0, high, low] if !ip.is_loopback() && !ip.is_unspecified() => {
+                    return is_private_ip(ipv4(high, low))
+                }
+                // 6to4
+                [0x2002, high, low, ..] => return is_private_ip(ipv4(high, low)),
+                _ => {}
+            }
+            let first_segment = segments[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local
+                || (first_segment & 0xfe00) == 0xfc00
+                // link local
+                || (first_segment & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BlockedAddressError(IpAddr);
+
+impl fmt::Display for BlockedAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Requests to {} aren't allowed", self.0)
+    }
+}
+
+impl Error for BlockedAddressError {}
+
+/// Resolves hostnames, but refuses to give back addresses that the policy
+/// doesn't allow. Doing the check here means it also applies to redirects and
+/// can't be bypassed by a dns record that changes between checking and
+/// connecting.
+struct PolicyResolver {
+    policy: Arc<AddressPolicy>,
+}
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            let allowed: Vec<SocketAddr> = addrs
+                .iter()
+                .filter(|addr| policy.is_allowed(addr.ip()))
+                .copied()
+                .collect();
+            if allowed.is_empty() {
+                if let Some(addr) = addrs.first() {
+                    return Err(Box::new(BlockedAddressError(addr.ip())) as _);
+                }
+            }
+            Ok(Box::new(allowed.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Downloads images from urls with limits on how big they can be and how long
+/// it can take.
+pub struct RemoteFetcher {
+    client: reqwest::Client,
+    policy: Arc<AddressPolicy>,
+    max_bytes: u64,
+}
+
+impl RemoteFetcher {
+    pub fn new(policy: AddressPolicy, max_bytes: u64, timeout: Duration) -> RemoteFetcher {
+        let policy = Arc::new(policy);
+        let redirect_policy = policy.clone();
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            // a proxy would be the one resolving hostnames, which skips the
+            // policy
+            .no_proxy()
+            .dns_resolver(Arc::new(PolicyResolver {
+                policy: policy.clone(),
+            }))
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    return attempt.error("Too many redirects");
+                }
+                match redirect_policy.check_url(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e),
+                }
+            }))
+            .build()
+            .expect("Failed to build http client");
+        RemoteFetcher {
+            client,
+            policy,
+            max_bytes,
+        }
+    }
+
+    /// Create a fetcher with the `url_upload` settings from the config.
+    pub fn from_config(config: &UrlUploadConfig) -> Result<RemoteFetcher, String> {
+        Ok(RemoteFetcher::new(
+            AddressPolicy {
+                allowlist: config.allowed_networks()?,
+            },
+            config.max_bytes,
+            Duration::from_secs(config.timeout_secs),
+        ))
+    }
+
+    /// Download the url and write the response body to `path`.
+    pub async fn fetch_to_file(&self, url: &str, path: &Path) -> Result<(), UploadError> {
+        let url =
+            Url::parse(url).map_err(|e| UploadError::BadRequest(format!("Invalid url: {}", e)))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(UploadError::BadRequest(
+                "Only http and https urls are supported".to_string(),
+            ));
+        }
+        self.policy
+            .check_url(&url)
+            .map_err(|e| UploadError::Forbidden(e.to_string()))?;
+
+        let response = self.client.get(url).send().await.map_err(fetch_error)?;
+        if !response.status().is_success() {
+            return Err(UploadError::BadGateway(format!(
+                "The server responded with {}",
+                response.status()
+            )));
+        }
+        if response.content_length().unwrap_or(0) > self.max_bytes {
+            return Err(self.too_large_error());
+        }
+
+        let mut file = tokio::fs::File::create(path)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut written = 0;
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(fetch_error)?;
+            // the content length can lie, so we have to count too
+            written += chunk.len() as u64;
+            if written > self.max_bytes {
+                return Err(self.too_large_error());
+            }
+            file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+        }
+        file.flush().await.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn too_large_error(&self) -> UploadError {
+        UploadError::PayloadTooLarge(format!("The image is bigger than {} bytes", self.max_bytes))
+    }
+}
+
+/// Turn an error from reqwest into an [`UploadError`], checking whether it was
+/// caused by the address being blocked.
+fn fetch_error(error: reqwest::Error) -> UploadError {
+    let mut source: Option<&(dyn Error + 'static)> = Some(&error);
+    while let Some(e) = source {
+        if let Some(blocked) = e.downcast_ref::<BlockedAddressError>() {
+            return UploadError::Forbidden(blocked.to_string());
+        }
+        source = e.source();
+    }
+    if error.is_timeout() {
+        return UploadError::BadGateway("Timed out downloading the image".to_string());
+    }
+    UploadError::BadGateway(format!("Error downloading the image: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    /// Serve one http response with the body on localhost, returning the url.
+    async fn serve_once(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            // wait for the whole request before responding
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    return;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            );
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&body).await.unwrap();
+        });
+        format!("http://{}/image.png", addr)
+    }
+
+    fn fetcher(allowlist: &[&str], max_bytes: u64) -> RemoteFetcher {
+        RemoteFetcher::new(
+            AddressPolicy {
+                allowlist: allowlist.iter().map(|net| net.parse().unwrap()).collect(),
+            },
+            max_bytes,
+            Duration::from_secs(5),
+        )
+    }
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("image-host-test-{}", util::generate_api_key()))
+    }
+
+    #[test]
+    fn private_ips_are_blocked() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::",
+            "2002:a00:1::1",
+            "::127.0.0.1",
+            "::10.0.0.1",
+        ] {
+            assert!(is_private_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "1.1.1.1",
+            "93.184.216.34",
+            "198.20.0.1",
+            "2606:4700:4700::1111",
+            "64:ff9b::101:101",
+            "2002:101:101::",
+        ] {
+            assert!(!is_private_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_allowlisted_localhost() {
+        let url = serve_once(b"not really a png".to_vec()).await;
+        let path = temp_path();
+        fetcher(&["127.0.0.1/32"], 1024)
+            .fetch_to_file(&url, &path)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"not really a png");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetch_localhost_blocked_by_default() {
+        let url = serve_once(b"secret".to_vec()).await;
+        let result = fetcher(&[], 1024).fetch_to_file(&url, &temp_path()).await;
+        assert!(matches!(result, Err(UploadError::Forbidden(_))));
+
+        let result = fetcher(&[], 1024)
+            .fetch_to_file("http://localhost:1/image.png", &temp_path())
+            .await;
+        assert!(matches!(result, Err(UploadError::Forbidden(_))));
+    }
+
+    #[tokio::test]
+    async fn fetch_too_large() {
+        let url = serve_once(vec![0; 2048]).await;
+        let path = temp_path();
+        let result = fetcher(&["127.0.0.1/32"], 1024)
+            .fetch_to_file(&url, &path)
+            .await;
+        assert!(matches!(result, Err(UploadError::PayloadTooLarge(_))));
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,404 @@
+//! Finding images that look the same, like re-uploads of a picture at a
+//! different size or in a different format.
+//!
+//! Every image has a 64-bit [dHash](https://www.hackerfactor.com/blog/index.php?/archives/529-Kind-of-Like-That.html)
+//! of its thumbnail, and similar images have hashes with a small Hamming
+//! distance. The hashes are kept in a BK-tree so searching doesn't have to
+//! compare against every image.
+
+use crate::auth::AdminKey;
+use crate::background_optimization::thumbnail_options_for_level;
+use crate::config::{Config, ImageConfig};
+use crate::error::UploadError;
+use crate::util::ImageId;
+use crate::{db, encoding, temp_upload_path};
+use futures::stream::TryStreamExt;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use mongodb::Collection;
+use rocket::data::{Limits, ToByteUnit};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{Data, Route, State};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+use tracing::info;
+
+lazy_static! {
+    /// The hashes of every image in the database. This is loaded when the
+    /// server starts, and kept up to date by [`db::insert_new_image`] and [`db::update_image`].
+    pub static ref INDEX: RwLock<SimilarityIndex> = RwLock::new(SimilarityIndex::default());
+}
+
+/// The default max Hamming distance for images to count as similar.
+pub const DEFAULT_MAX_DISTANCE: u32 = 10;
+
+/// Compute the difference hash of an image. Each bit is whether a pixel is
+/// brighter than the one to its right, in a 9x8 grayscale version of the
+/// image.
+pub fn dhash(im: &DynamicImage) -> u64 {
+    let small = im.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    hash
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+struct Node {
+    hash: u64,
+    /// The images that have exactly this hash
+    ids: Vec<String>,
+    /// The children of the node, by their distance from it
+    children: HashMap<u32, Node>,
+}
+
+/// A BK-tree of perceptual hashes. Since the Hamming distance follows the
+/// triangle inequality, a search only has to visit the children whose distance
+/// from the parent is within the max distance of the query's distance from
+/// the parent.
+#[derive(Default)]
+pub struct BkTree {
+    root: Option<Node>,
+}
+
+impl BkTree {
+    pub fn insert(&mut self, hash: u64, id: String) {
+        let Some(mut node) = self.root.as_mut() else {
+            self.root = Some(Node {
+                hash,
+                ids: vec![id],
+                children: HashMap::new(),
+            });
+            return;
+        };
+        loop {
+            let distance = hamming_distance(node.hash, hash);
+            if distance == 0 {
+                node.ids.push(id);
+                return;
+            }
+            match node.children.entry(distance) {
+                Entry::Occupied(child) => node = child.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(Node {
+                        hash,
+                        ids: vec![id],
+                        children: HashMap::new(),
+                    });
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Find every id with a hash within `max_distance` of the given hash,
+    /// along with the hash that it was inserted with.
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(&str, u64)> {
+        let mut found = Vec::new();
+        let mut stack: Vec<&Node> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            let distance = hamming_distance(node.hash, hash);
+            if distance <= max_distance {
+                found.extend(node.ids.iter().map(|id| (id.as_str(), node.hash)));
+            }
+            let range = distance.saturating_sub(max_distance)..=distance + max_distance;
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| range.contains(*d))
+                    .map(|(_, child)| child),
+            );
+        }
+        found
+    }
+}
+
+/// The perceptual hashes of images and a tree for searching them. Nodes can't
+/// be removed from a BK-tree, so when an image's hash changes the old entry
+/// is left in the tree and ignored.
+#[derive(Default)]
+pub struct SimilarityIndex {
+    tree: BkTree,
+    hashes: HashMap<String, u64>,
+}
+
+impl SimilarityIndex {
+    pub fn insert(&mut self, id: &str, hash: u64) {
+        if self.hashes.get(id) == Some(&hash) {
+            return;
+        }
+        self.hashes.insert(id.to_string(), hash);
+        self.tree.insert(hash, id.to_string());
+    }
+
+    /// Find images with a hash within `max_distance` of the given one, closest
+    /// first.
+    pub fn search(&self, hash: u64, max_distance: u32) -> Vec<(ImageId, u32)> {
+        let mut found: Vec<(ImageId, u32)> = self
+            .tree
+            .find(hash, max_distance)
+            .into_iter()
+            .filter(|(id, inserted_hash)| self.hashes.get(*id) == Some(inserted_hash))
+            .map(|(id, inserted_hash)| {
+                (
+                    ImageId(id.to_string()),
+                    hamming_distance(hash, inserted_hash),
+                )
+            })
+            .collect();
+        found.sort_by(|(a_id, a), (b_id, b)| a.cmp(b).then_with(|| a_id.0.cmp(&b_id.0)));
+        found
+    }
+
+    /// Stop returning an image from searches, like after it's deleted.
+    pub fn remove(&mut self, id: &str) {
+        self.hashes.remove(id);
+    }
+
+    pub fn get(&self, id: &str) -> Option<u64> {
+        self.hashes.get(id).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+}
+
+/// Read the perceptual hash stored in an image document.
+pub fn hash_from_doc(image_doc: &Document) -> Option<u64> {
+    image_doc.get_i64("dhash").ok().map(|hash| hash as u64)
+}
+
+/// Add the hashes of every image in the database to [`INDEX`].
+pub async fn load_index(images_collection: &Collection<Document>) -> Result<(), String> {
+    let docs: Vec<Document> = images_collection
+        .find(
+            doc! {"dhash": {"$exists": true}},
+            FindOptions::builder()
+                .projection(doc! {"_id": 1, "dhash": 1})
+                .build(),
+        )
+        .await
+        .map_err(|e| e.to_string())?
+        .try_collect()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut index = INDEX.write().unwrap();
+    for image_doc in &docs {
+        if let (Ok(id), Some(hash)) = (image_doc.get_str("_id"), hash_from_doc(image_doc)) {
+            index.insert(id, hash);
+        }
+    }
+    info!(count = index.len(), "Loaded perceptual hashes");
+    Ok(())
+}
+
+/// Find the images that look like the image with the given id, not including
+/// itself. Returns None if the image doesn't exist or doesn't have a hash.
+pub async fn similar_to_id(
+    images_collection: &Collection<Document>,
+    id: &str,
+    max_distance: u32,
+) -> Result<Option<Vec<(ImageId, u32)>>, String> {
+    let indexed_hash = INDEX.read().unwrap().get(id);
+    let hash = match indexed_hash {
+        Some(hash) => Some(hash),
+        // it might have been uploaded by another process
+        None => db::get_image_metadata(images_collection, id)
+            .await
+            .map_err(|e| e.to_string())?
+            .as_ref()
+            .and_then(hash_from_doc),
+    };
+    Ok(hash.map(|hash| {
+        INDEX
+            .read()
+            .unwrap()
+            .search(hash, max_distance)
+            .into_iter()
+            .filter(|(similar_id, _)| similar_id.0 != id)
+            .collect()
+    }))
+}
+
+/// Compute the perceptual hash of an image file the same way it's done for
+/// uploads, so it can be compared with the stored ones.
+pub async fn hash_file(path: &Path, config: &ImageConfig) -> Result<u64, UploadError> {
+    let format = encoding::detect_format(path, None).await?;
+    encoding::check_dimensions(path, format).await?;
+    let thumbnail = encoding::image_path_to_encoded(
+        Box::new(path.to_path_buf()),
+        format,
+        thumbnail_options_for_level(0, config),
+    )
+    .await?;
+    Ok(thumbnail
+        .perceptual_hash
+        .expect("thumbnails always have a perceptual hash"))
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SimilarImage {
+    pub id: String,
+    /// How many bits of the perceptual hashes are different, out of 64
+    pub distance: u32,
+    pub url: String,
+}
+
+impl SimilarImage {
+    pub fn new((id, distance): (ImageId, u32), host: &str) -> SimilarImage {
+        SimilarImage {
+            url: format!("https://{}/{}", host, id),
+            id: id.0,
+            distance,
+        }
+    }
+}
+
+/// Find images that look like an existing image.
+#[get("/api/admin/similar/<id>?<distance>")]
+async fn similar_to_id_route(
+    id: &str,
+    distance: Option<u32>,
+    admin: AdminKey,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+) -> Result<Option<Json<Vec<SimilarImage>>>, UploadError> {
+    info!(
+        admin = admin.0.name,
+        image_id = id,
+        "Looking for similar images"
+    );
+    let similar = similar_to_id(
+        &collections.images,
+        id,
+        distance.unwrap_or(DEFAULT_MAX_DISTANCE),
+    )
+    .await?;
+    Ok(similar.map(|similar| {
+        Json(
+            similar
+                .into_iter()
+                .map(|found| SimilarImage::new(found, &config.host))
+                .collect(),
+        )
+    }))
+}
+
+/// Find images that look like the image in the request body.
+#[post("/api/admin/similar?<distance>", data = "<data>")]
+async fn similar_to_sample_route(
+    distance: Option<u32>,
+    data: Data<'_>,
+    limits: &Limits,
+    admin: AdminKey,
+    config: &State<Config>,
+) -> Result<Json<Vec<SimilarImage>>, UploadError> {
+    info!(
+        admin = admin.0.name,
+        "Looking for images similar to a sample"
+    );
+    let path = temp_upload_path();
+    let hash_result = async {
+        let limit = limits.get("file").unwrap_or(16.mebibytes());
+        let file = data
+            .open(limit)
+            .into_file(&path)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !file.is_complete() {
+            return Err(UploadError::PayloadTooLarge(format!(
+                "The image is bigger than {}",
+                limit
+            )));
+        }
+        hash_file(&path, &config.images).await
+    }
+    .await;
+    tokio::fs::remove_file(&path).await.ok();
+
+    let similar = INDEX
+        .read()
+        .unwrap()
+        .search(hash_result?, distance.unwrap_or(DEFAULT_MAX_DISTANCE));
+    Ok(Json(
+        similar
+            .into_iter()
+            .map(|found| SimilarImage::new(found, &config.host))
+            .collect(),
+    ))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![similar_to_id_route, similar_to_sample_route]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn resized_image_has_close_hash() {
+        let im = DynamicImage::ImageRgb8(RgbImage::from_fn(90, 80, |x, y| {
+            Rgb([(x * 2) as u8, (y * 3) as u8, ((x * y) % 256) as u8])
+        }));
+        let smaller = im.resize_exact(45, 40, FilterType::Lanczos3);
+        let flipped = im.fliph();
+        assert!(hamming_distance(dhash(&im), dhash(&smaller)) <= 4);
+        assert!(hamming_distance(dhash(&im), dhash(&flipped)) > DEFAULT_MAX_DISTANCE);
+    }
+
+    #[test]
+    fn index_matches_linear_scan() {
+        let hashes: Vec<u64> = (0..500u64)
+            .map(|i| i.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+            .collect();
+        let mut index = SimilarityIndex::default();
+        for (i, hash) in hashes.iter().enumerate() {
+            index.insert(&i.to_string(), *hash);
+        }
+
+        let query = hashes[42] ^ 0b1011;
+        for max_distance in [0, 3, 20, 30] {
+            let mut expected: Vec<(String, u32)> = hashes
+                .iter()
+                .enumerate()
+                .map(|(i, hash)| (i.to_string(), hamming_distance(query, *hash)))
+                .filter(|(_, distance)| *distance <= max_distance)
+                .collect();
+            expected.sort_by(|(a_id, a), (b_id, b)| a.cmp(b).then_with(|| a_id.cmp(b_id)));
+            let found: Vec<(String, u32)> = index
+                .search(query, max_distance)
+                .into_iter()
+                .map(|(id, distance)| (id.0, distance))
+                .collect();
+            assert_eq!(found, expected, "max distance {}", max_distance);
+        }
+    }
+
+    #[test]
+    fn changed_hash_replaces_old_one() {
+        let mut index = SimilarityIndex::default();
+        index.insert("a", 0);
+        index.insert("a", u64::MAX);
+        assert!(index.search(0, 0).is_empty());
+        assert_eq!(index.search(u64::MAX, 0).len(), 1);
+        index.remove("a");
+        assert!(index.search(u64::MAX, 0).is_empty());
+    }
+}
@@ -0,0 +1,523 @@
+//! Resumable uploads with the tus 1.0 protocol (https://tus.io/protocols/resumable-upload),
+//! for big images over connections that might drop. Uploads are written to a
+//! temporary file in chunks, and when the last chunk arrives the file is
+//! handed to [`upload_image_with_id`].
+//!
+//! We support the creation, expiration and termination extensions.
+
+use crate::config::{Config, TusConfig};
+use crate::error::UploadError;
+use crate::logging::RequestSpan;
+use crate::visibility::Visibility;
+use crate::{db, ids, upload_image_with_id, util, ApiUploadResult};
+use base64::{engine::general_purpose, Engine};
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::serde_json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Route, State};
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tracing::Instrument;
+use util::ImageId;
+
+const TUS_VERSION: &str = "1.0.0";
+
+/// Where in-progress uploads are kept, and the limits on them.
+pub struct TusStore {
+    dir: PathBuf,
+    max_size: u64,
+    /// How long an upload can go without receiving data before it's deleted
+    expiry: Duration,
+    /// Uploads that are currently receiving a chunk, so two requests can't
+    /// write to the same upload at once
+    locked: Mutex<HashSet<String>>,
+}
+
+/// The information about an upload that we can't get from the file itself,
+/// stored next to it as json.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct UploadInfo {
+    length: u64,
+    metadata: HashMap<String, String>,
+}
+
+impl TusStore {
+    /// Create a store with the `tus` settings from the config, making its
+    /// directory if it doesn't exist.
+    pub fn new(config: &TusConfig) -> Result<TusStore, String> {
+        std::fs::create_dir_all(&config.dir).map_err(|e| {
+            format!(
+                "Failed to create the tus upload directory {}: {}",
+                config.dir.display(),
+                e
+            )
+        })?;
+        Ok(TusStore {
+            dir: config.dir.clone(),
+            max_size: config.max_size,
+            expiry: Duration::from_secs(config.expiry_secs),
+            locked: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// The directory in-progress uploads are written to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    async fn get_info(&self, id: &str) -> Option<UploadInfo> {
+        // the id ends up in a path, so make sure it can't escape the directory
+        if !util::is_valid_id(id) {
+            return None;
+        }
+        let info = fs::read(self.info_path(id)).await.ok()?;
+        serde_json::from_slice(&info).ok()
+    }
+
+    /// How many bytes of the upload we've received
+    async fn get_offset(&self, id: &str) -> Option<u64> {
+        fs::metadata(self.data_path(id)).await.ok().map(|m| m.len())
+    }
+
+    /// When the upload will be deleted if it doesn't receive any more data
+    async fn get_expiry(&self, id: &str) -> Option<SystemTime> {
+        let modified = fs::metadata(self.data_path(id))
+            .await
+            .ok()?
+            .modified()
+            .ok()?;
+        Some(modified + self.expiry)
+    }
+
+    async fn remove(&self, id: &str) {
+        fs::remove_file(self.data_path(id)).await.ok();
+        fs::remove_file(self.info_path(id)).await.ok();
+    }
+
+    /// Lock the upload until the returned guard is dropped, or `None` if it's
+    /// already locked.
+    fn lock(&self, id: &str) -> Option<UploadLock<'_>> {
+        if !self.locked.lock().unwrap().insert(id.to_string()) {
+            return None;
+        }
+        Some(UploadLock {
+            store: self,
+            id: id.to_string(),
+        })
+    }
+
+    /// Delete uploads that haven't received data in a while, returning how
+    /// many were deleted.
+    pub async fn remove_expired(&self) -> Result<usize, std::io::Error> {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(id) = entry.file_name().to_str().map(|s| s.to_string()) else {
+                continue;
+            };
+            if id.ends_with(".json") {
+                continue;
+            }
+            // uploads that are receiving data are skipped, and holding the
+            // lock keeps one from starting while it's being removed
+            let Some(_lock) = self.lock(&id) else {
+                continue;
+            };
+            if matches!(self.get_expiry(&id).await, Some(expiry) if expiry < now) {
+                self.remove(&id).await;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// A locked upload, which is unlocked when this is dropped. That includes when
+/// a request is cancelled partway through, so an upload can't stay locked.
+struct UploadLock<'a> {
+    store: &'a TusStore,
+    id: String,
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        self.store.locked.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Parse the Upload-Metadata header, which is a comma separated list of keys
+/// and base64 values.
+fn parse_metadata(header: &str) -> Result<HashMap<String, String>, String> {
+    let mut metadata = HashMap::new();
+    for pair in header.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, value)) => {
+                let value = general_purpose::STANDARD
+                    .decode(value.trim())
+                    .map_err(|_| format!("Invalid base64 for metadata key {}", key))?;
+                (key, String::from_utf8_lossy(&value).to_string())
+            }
+            None => (pair, String::new()),
+        };
+        metadata.insert(key.to_string(), value);
+    }
+    Ok(metadata)
+}
+
+/// The tus headers from a request.
+pub struct TusHeaders {
+    resumable: Option<String>,
+    upload_length: Option<String>,
+    upload_offset: Option<String>,
+    upload_metadata: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusHeaders {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let header = |name| request.headers().get_one(name).map(|v| v.to_string());
+        request::Outcome::Success(TusHeaders {
+            resumable: header("Tus-Resumable"),
+            upload_length: header("Upload-Length"),
+            upload_offset: header("Upload-Offset"),
+            upload_metadata: header("Upload-Metadata"),
+        })
+    }
+}
+
+impl TusHeaders {
+    /// Every request other than OPTIONS has to say which version it's using.
+    fn check_version(&self) -> Result<(), TusResponse> {
+        if self.resumable.as_deref() != Some(TUS_VERSION) {
+            return Err(TusResponse::new(Status::PreconditionFailed)
+                .header("Tus-Version", TUS_VERSION)
+                .body("Unsupported tus version"));
+        }
+        Ok(())
+    }
+}
+
+/// A response with the headers tus clients expect.
+pub struct TusResponse {
+    status: Status,
+    headers: Vec<Header<'static>>,
+    body: Option<String>,
+}
+
+impl TusResponse {
+    fn new(status: Status) -> TusResponse {
+        TusResponse {
+            status,
+            headers: vec![Header::new("Tus-Resumable", TUS_VERSION)],
+            body: None,
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> TusResponse {
+        self.headers.push(Header::new(name, value.to_string()));
+        self
+    }
+
+    fn body(mut self, body: impl ToString) -> TusResponse {
+        self.body = Some(body.to_string());
+        self
+    }
+}
+
+impl From<UploadError> for TusResponse {
+    fn from(error: UploadError) -> Self {
+        TusResponse::new(error.status()).body(error.message())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.status(self.status);
+        for header in self.headers {
+            response.header(header);
+        }
+        if let Some(body) = self.body {
+            response.sized_body(body.len(), Cursor::new(body));
+        }
+        Ok(response.finalize())
+    }
+}
+
+/// The routes for tus uploads. These need an `Arc<TusStore>` to be managed.
+pub fn routes() -> Vec<Route> {
+    routes![
+        tus_options_route,
+        tus_create_route,
+        tus_head_route,
+        tus_patch_route,
+        tus_delete_route,
+    ]
+}
+
+fn unwrap_response(result: Result<TusResponse, TusResponse>) -> TusResponse {
+    result.unwrap_or_else(|response| response)
+}
+
+#[options("/api/tus")]
+fn tus_options_route(store: &State<Arc<TusStore>>) -> TusResponse {
+    TusResponse::new(Status::NoContent)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Max-Size", store.max_size)
+        .header("Tus-Extension", "creation,expiration,termination")
+}
+
+/// Create a new upload. The client says how big the file will be and then
+/// sends it with PATCH requests to the url in the Location header.
+///
+/// The `visibility` in the Upload-Metadata can be `public` or `unlisted`.
+/// Private images need an API key, so they can't be uploaded with tus.
+#[post("/api/tus")]
+async fn tus_create_route(
+    headers: TusHeaders,
+    store: &State<Arc<TusStore>>,
+    config: &State<Config>,
+) -> TusResponse {
+    unwrap_response(
+        async {
+            headers.check_version()?;
+            let length: u64 = headers
+                .upload_length
+                .as_deref()
+                .and_then(|l| l.parse().ok())
+                .ok_or_else(|| {
+                    TusResponse::new(Status::BadRequest).body("Upload-Length is required")
+                })?;
+            if length > store.max_size {
+                return Err(TusResponse::new(Status::PayloadTooLarge).body(format!(
+                    "Uploads can't be bigger than {} bytes",
+                    store.max_size
+                )));
+            }
+            let metadata = parse_metadata(headers.upload_metadata.as_deref().unwrap_or(""))
+                .map_err(|e| TusResponse::new(Status::BadRequest).body(e))?;
+            Visibility::requested(
+                metadata.get("visibility").map(String::as_str),
+                None,
+                &config.signing,
+            )?;
+
+            // the id is all it takes to add to an upload, so it has to be
+            // as hard to guess as a private image's
+            let id = ids::random_id(&config.ids, Visibility::Private).0;
+            let info = serde_json::to_vec(&UploadInfo { length, metadata })
+                .map_err(|e| UploadError::Internal(e.to_string()))?;
+            fs::write(store.info_path(&id), info)
+                .await
+                .map_err(|e| UploadError::Internal(e.to_string()))?;
+            fs::write(store.data_path(&id), b"")
+                .await
+                .map_err(|e| UploadError::Internal(e.to_string()))?;
+
+            let expires = SystemTime::now() + store.expiry;
+            Ok(TusResponse::new(Status::Created)
+                .header("Location", format!("/api/tus/{}", id))
+                .header("Upload-Expires", httpdate::fmt_http_date(expires)))
+        }
+        .await,
+    )
+}
+
+/// Get how much of the upload we've received, so the client knows where to
+/// resume from.
+#[head("/api/tus/<id>")]
+async fn tus_head_route(
+    id: &str,
+    headers: TusHeaders,
+    store: &State<Arc<TusStore>>,
+) -> TusResponse {
+    unwrap_response(
+        async {
+            headers.check_version()?;
+            let (info, offset) = match (store.get_info(id).await, store.get_offset(id).await) {
+                (Some(info), Some(offset)) => (info, offset),
+                _ => return Err(TusResponse::new(Status::NotFound)),
+            };
+            let mut response = TusResponse::new(Status::Ok)
+                .header("Upload-Offset", offset)
+                .header("Upload-Length", info.length)
+                .header("Cache-Control", "no-store");
+            if let Some(expires) = store.get_expiry(id).await {
+                response = response.header("Upload-Expires", httpdate::fmt_http_date(expires));
+            }
+            Ok(response)
+        }
+        .await,
+    )
+}
+
+/// Receive the next chunk of an upload. When the upload is complete the image
+/// gets encoded, and the response has the usual upload result as json.
+#[patch("/api/tus/<id>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn tus_patch_route(
+    id: &str,
+    headers: TusHeaders,
+    content_type: Option<&ContentType>,
+    data: Data<'_>,
+    store: &State<Arc<TusStore>>,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+    request_span: &RequestSpan,
+) -> TusResponse {
+    if let Err(response) = headers.check_version() {
+        return response;
+    }
+    if content_type.map(|t| t.to_string()).as_deref() != Some("application/offset+octet-stream") {
+        return TusResponse::new(Status::UnsupportedMediaType)
+            .body("The Content-Type must be application/offset+octet-stream");
+    }
+    let Some(info) = store.get_info(id).await else {
+        return TusResponse::new(Status::NotFound);
+    };
+    let Some(_lock) = store.lock(id) else {
+        return TusResponse::new(Status::Conflict).body("The upload is already receiving data");
+    };
+    unwrap_response(
+        patch_upload(id, &headers, info, data, store, collections, config)
+            .instrument(request_span.span.clone())
+            .await,
+    )
+}
+
+async fn patch_upload(
+    id: &str,
+    headers: &TusHeaders,
+    info: UploadInfo,
+    data: Data<'_>,
+    store: &TusStore,
+    collections: &db::Collections,
+    config: &Config,
+) -> Result<TusResponse, TusResponse> {
+    let offset = store
+        .get_offset(id)
+        .await
+        .ok_or_else(|| TusResponse::new(Status::NotFound))?;
+    let client_offset: Option<u64> = headers
+        .upload_offset
+        .as_deref()
+        .and_then(|o| o.parse().ok());
+    if client_offset != Some(offset) {
+        return Err(TusResponse::new(Status::Conflict)
+            .header("Upload-Offset", offset)
+            .body("Upload-Offset doesn't match how much has been uploaded"));
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(store.data_path(id))
+        .await
+        .map_err(|e| UploadError::Internal(e.to_string()))?;
+    // if the connection drops partway through, whatever we got is kept and
+    // the client can resume from there
+    let written = data
+        .open((info.length - offset).bytes())
+        .stream_to(&mut file)
+        .await;
+    tokio::io::AsyncWriteExt::flush(&mut file).await.ok();
+    let offset = offset + written.map(|n| n.written).unwrap_or(0);
+    let offset = store.get_offset(id).await.unwrap_or(offset);
+
+    if offset < info.length {
+        return Ok(TusResponse::new(Status::NoContent).header("Upload-Offset", offset));
+    }
+
+    // the upload is done, so now it's just like any other upload
+    let visibility = Visibility::requested(
+        info.metadata.get("visibility").map(String::as_str),
+        None,
+        &config.signing,
+    )?;
+    let upload_result = upload_image_with_id(
+        store.data_path(id),
+        info.metadata.get("filetype").cloned(),
+        collections,
+        config,
+        None,
+        visibility,
+        None,
+    )
+    .await;
+    store.remove(id).await;
+    let image_id: ImageId = upload_result?;
+    let result = serde_json::to_string(&ApiUploadResult::new(&image_id, config, visibility))
+        .map_err(|e| UploadError::Internal(e.to_string()))?;
+    Ok(TusResponse::new(Status::Ok)
+        .header("Upload-Offset", offset)
+        .header("Content-Type", "application/json")
+        .body(result))
+}
+
+/// Cancel an upload and delete what's been uploaded so far.
+#[delete("/api/tus/<id>")]
+async fn tus_delete_route(
+    id: &str,
+    headers: TusHeaders,
+    store: &State<Arc<TusStore>>,
+) -> TusResponse {
+    if let Err(response) = headers.check_version() {
+        return response;
+    }
+    if store.get_info(id).await.is_none() {
+        return TusResponse::new(Status::NotFound);
+    }
+    let Some(_lock) = store.lock(id) else {
+        return TusResponse::new(Status::Conflict).body("The upload is receiving data");
+    };
+    store.remove(id).await;
+    TusResponse::new(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn parse_metadata_works() {
+        let metadata =
+            parse_metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,is_confidential")
+                .unwrap();
+        assert_eq!(metadata["filename"], "world_domination_plan.pdf");
+        assert_eq!(metadata["is_confidential"], "");
+        assert!(parse_metadata("").unwrap().is_empty());
+        assert!(parse_metadata("filetype !!!").is_err());
+    }
+
+    #[test]
+    fn locks_are_released_when_dropped() {
+        let config = TusConfig {
+            dir: std::env::temp_dir().join(format!("image-host-test-{}", util::generate_api_key())),
+            ..TusConfig::default()
+        };
+        let store = TusStore::new(&config).unwrap();
+        let lock = store.lock("abcde").unwrap();
+        assert!(store.lock("abcde").is_none());
+        assert!(store.lock("fghjk").is_some());
+        drop(lock);
+        assert!(store.lock("abcde").is_some());
+        std::fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
@@ -1,8 +1,10 @@
 //! Useful things that aren't entirely specific to this project.
 
+use base64::{engine::general_purpose, Engine};
 use image::ImageFormat;
 use mongodb::bson::Bson;
 use rand::Rng;
+use sha2::{Digest, Sha256};
 use std::fmt;
 
 /// Generate a random string of the given length using the given charset.
@@ -45,40 +47,191 @@ impl fmt::Display for ImageId {
     }
 }
 
+/// The characters random ids are made of, unless the config picks others.
+pub const ID_CHARSET: &[u8] = b"bcdfghjklmnpqrstvwxyzBCDFGHJKLMNPQRSTVWXYZ0123456789-_";
+
 /// Generate a random string meant to be used as an id.
 pub fn generate_random_id(length: usize) -> ImageId {
-    ImageId(generate_random_string(
-        length,
-        b"bcdfghjklmnpqrstvwxyzBCDFGHJKLMNPQRSTVWXYZ0123456789-_",
-    ))
+    ImageId(generate_random_string(length, ID_CHARSET))
 }
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod test {
     use super::*;
     #[test]
     fn generate_random_id_works() {
         assert_eq!(generate_random_id(5).0.len(), 5);
     }
+    #[test]
+    fn generated_ids_are_valid() {
+        assert!(is_valid_id(&generate_random_id(5).0));
+        assert!(!is_valid_id(""));
+        assert!(!is_valid_id("has space"));
+        assert!(!is_valid_id("../etc"));
+    }
+    #[test]
+    fn upload_format_uses_detected_format() {
+        let format = check_upload_format(Some(ImageFormat::Png), Some("image/png"));
+        assert_eq!(format, Ok(ImageFormat::Png));
+        let format = check_upload_format(Some(ImageFormat::Png), Some("application/octet-stream"));
+        assert_eq!(format, Ok(ImageFormat::Png));
+        let format = check_upload_format(Some(ImageFormat::Jpeg), None);
+        assert_eq!(format, Ok(ImageFormat::Jpeg));
+    }
+    #[test]
+    fn decode_base64_image_works() {
+        assert_eq!(
+            decode_base64_image("aGVsbG8="),
+            Ok((None, b"hello".to_vec()))
+        );
+        assert_eq!(
+            decode_base64_image("data:image/png;base64,aGVs\nbG8="),
+            Ok((Some("image/png".to_string()), b"hello".to_vec()))
+        );
+        assert!(decode_base64_image("data:image/png,hello").is_err());
+        assert!(decode_base64_image("not base64!").is_err());
+    }
+    #[test]
+    fn mimetypes_round_trip() {
+        for format in [
+            ImageFormat::Png,
+            ImageFormat::Jpeg,
+            ImageFormat::Gif,
+            ImageFormat::WebP,
+            ImageFormat::Pnm,
+            ImageFormat::Tiff,
+            ImageFormat::Tga,
+            ImageFormat::Dds,
+            ImageFormat::Bmp,
+            ImageFormat::Ico,
+            ImageFormat::Hdr,
+            ImageFormat::OpenExr,
+            ImageFormat::Farbfeld,
+            ImageFormat::Avif,
+            ImageFormat::Qoi,
+        ]
+        .into_iter()
+        // farbfeld doesn't have a mime type, it's checked below
+        .filter(|format| format.can_read() && *format != ImageFormat::Farbfeld)
+        {
+            assert_eq!(
+                mimetype_to_format(format.to_mime_type()),
+                Some(format),
+                "{:?}",
+                format
+            );
+        }
+        for (mimetype, format) in [
+            ("image/x-targa", ImageFormat::Tga),
+            ("image/x-exr", ImageFormat::OpenExr),
+            ("image/qoi", ImageFormat::Qoi),
+            ("image/x-qoi", ImageFormat::Qoi),
+            ("image/jpg", ImageFormat::Jpeg),
+            ("image/vnd.microsoft.icon", ImageFormat::Ico),
+            ("image/farbfeld", ImageFormat::Farbfeld),
+        ] {
+            assert_eq!(mimetype_to_format(mimetype), Some(format), "{}", mimetype);
+        }
+        assert_eq!(mimetype_to_format("image/made-up"), None);
+    }
+    #[test]
+    fn upload_format_rejects_mismatch() {
+        assert!(check_upload_format(Some(ImageFormat::Png), Some("image/jpeg")).is_err());
+        assert!(check_upload_format(Some(ImageFormat::Png), Some("image/made-up")).is_err());
+        assert!(check_upload_format(None, Some("image/png")).is_err());
+    }
+}
+
+/// Whether the string could be an id, from any alphabet `ids.alphabet` can be
+/// set to. These can't escape a directory when they're used in a path.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(is_id_char)
+}
+
+/// Whether the character is safe to put in an id, which is anything that
+/// doesn't need escaping in a url path.
+pub fn is_id_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'-' || c == b'_'
+}
+
+/// Generate a random secret API key.
+pub fn generate_api_key() -> String {
+    generate_random_string(
+        32,
+        b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789",
+    )
+}
+
+/// Hash some bytes with SHA-256 and return it as a hex string.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Convert a string mime type to an `ImageFormat`. This knows the mime types
+/// the `image` crate does, and some older or unofficial ones that clients
+/// still send.
+pub fn mimetype_to_format(mimetype: &str) -> Option<ImageFormat> {
+    ImageFormat::from_mime_type(mimetype).or(match mimetype {
+        "image/jpg" => Some(ImageFormat::Jpeg),
+        "image/pnm" => Some(ImageFormat::Pnm),
+        "image/tga" => Some(ImageFormat::Tga),
+        "image/dds" => Some(ImageFormat::Dds),
+        "image/ico" | "image/vnd.microsoft.icon" => Some(ImageFormat::Ico),
+        "image/hdr" => Some(ImageFormat::Hdr),
+        "image/qoi" => Some(ImageFormat::Qoi),
+        // the image crate doesn't have a mime type for farbfeld
+        "image/farbfeld" => Some(ImageFormat::Farbfeld),
+        _ => None,
+    })
+}
+
+/// Decode an image sent as base64 or as a base64 data uri. Returns the content
+/// type from the data uri if there was one, and the decoded bytes.
+pub fn decode_base64_image(encoded: &str) -> Result<(Option<String>, Vec<u8>), String> {
+    let (content_type, encoded) = match encoded.strip_prefix("data:") {
+        Some(data_uri) => {
+            let (metadata, encoded) = data_uri
+                .split_once(',')
+                .ok_or("Invalid data uri, there's no comma")?;
+            let content_type = metadata
+                .strip_suffix(";base64")
+                .ok_or("Only base64 data uris are supported")?;
+            let content_type = (!content_type.is_empty()).then(|| content_type.to_string());
+            (content_type, encoded)
+        }
+        None => (None, encoded),
+    };
+    // base64 is often wrapped over multiple lines
+    let encoded: String = encoded.chars().filter(|c| !c.is_whitespace()).collect();
+    let data = general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| format!("Invalid base64: {}", e))?;
+    Ok((content_type, data))
 }
 
-/// Convert a string mime type to an `ImageFormat`, default to Jpeg if not found.
-pub fn mimetype_to_format(mimetype: &str) -> ImageFormat {
-    match mimetype {
-        "image/png" => ImageFormat::Png,
-        "image/jpeg" => ImageFormat::Jpeg,
-        "image/gif" => ImageFormat::Gif,
-        "image/webp" => ImageFormat::WebP,
-        "image/pnm" => ImageFormat::Pnm,
-        "image/tiff" => ImageFormat::Tiff,
-        "image/tga" => ImageFormat::Tga,
-        "image/dds" => ImageFormat::Dds,
-        "image/bmp" => ImageFormat::Bmp,
-        "image/ico" => ImageFormat::Ico,
-        "image/hdr" => ImageFormat::Hdr,
-        "image/farbfeld" => ImageFormat::Farbfeld,
-        "image/avif" => ImageFormat::Avif,
-        // idk just go with jpeg it'll probably fail
-        _ => ImageFormat::Jpeg,
+/// Decide the format of an uploaded file from the format detected from its
+/// contents and the content type the client claimed it was. Clients that
+/// don't know the type can send `application/octet-stream`.
+pub fn check_upload_format(
+    detected: Option<ImageFormat>,
+    claimed_mimetype: Option<&str>,
+) -> Result<ImageFormat, String> {
+    let detected = match detected {
+        Some(format) if format.can_read() => format,
+        _ => return Err("Unsupported image format".to_string()),
+    };
+    // ignore parameters like "; charset=binary"
+    let claimed_mimetype = claimed_mimetype.map(|m| m.split(';').next().unwrap_or(m).trim());
+    match claimed_mimetype {
+        None | Some("application/octet-stream") => Ok(detected),
+        Some(mimetype) => match mimetype_to_format(mimetype) {
+            Some(claimed) if claimed == detected => Ok(detected),
+            _ => Err(format!(
+                "The file is {} but was uploaded as {}",
+                detected.to_mime_type(),
+                mimetype
+            )),
+        },
     }
 }
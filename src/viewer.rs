@@ -0,0 +1,134 @@
+//! An HTML page for viewing a single image, with the metadata chat apps and
+//! social sites use to embed it.
+
+use crate::config::Config;
+use crate::error::UploadError;
+use crate::visibility::{self, UrlSignature, Visibility};
+use crate::{db, metrics, moderation};
+use rocket::http::MediaType;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Request, Route, State};
+use rocket_dyn_templates::{context, Template};
+
+/// A request guard that only succeeds when the client would rather have an
+/// HTML page than the image itself, like a browser navigating to the url.
+/// Image clients (including browsers loading an `<img>`) don't put
+/// `text/html` first in their Accept header, so they still get the raw bytes.
+/// Adding `?raw` to the url always gets the raw bytes.
+struct PrefersHtml;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PrefersHtml {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let wants_raw = req.query_fields().any(|field| field.name == "raw");
+        let prefers_html = req
+            .accept()
+            .map(|accept| accept.preferred().media_type() == &MediaType::HTML)
+            .unwrap_or(false);
+        if prefers_html && !wants_raw {
+            Outcome::Success(PrefersHtml)
+        } else {
+            Outcome::Forward(())
+        }
+    }
+}
+
+async fn render_viewer(
+    collections: &db::Collections,
+    config: &Config,
+    id: &str,
+    signature: &UrlSignature,
+    reported: bool,
+) -> Result<Option<Template>, UploadError> {
+    let Some(image_doc) = db::get_image_metadata(&collections.images, id).await? else {
+        return Ok(None);
+    };
+    moderation::check_not_disabled(&image_doc)?;
+    visibility::check_access(&image_doc, signature)?;
+
+    metrics::VIEWS.with_label_values(&["viewer"]).inc();
+    Ok(Some(Template::render(
+        "viewer",
+        context! {
+            host: &config.host,
+            id: id,
+            content_type: image_doc.get_str("content_type").map_err(|e| e.to_string())?,
+            width: image_doc.get_i32("width").map_err(|e| e.to_string())?,
+            height: image_doc.get_i32("height").map_err(|e| e.to_string())?,
+            reported: reported,
+            // so search engines don't list images that aren't public
+            public: Visibility::from_doc(&image_doc) == Visibility::Public,
+            // links to private images need the signature from this page's url
+            signed_query: signature.query(),
+        },
+    )))
+}
+
+/// `reported` is set after using the report form, to thank the reporter.
+#[get("/v/<id>?<reported>")]
+async fn viewer_route(
+    id: &str,
+    reported: Option<&str>,
+    signature: UrlSignature,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+) -> Result<Option<Template>, UploadError> {
+    render_viewer(collections, config, id, &signature, reported.is_some()).await
+}
+
+#[get("/<id>", rank = 1)]
+async fn viewer_for_browser_route(
+    id: &str,
+    _prefers_html: PrefersHtml,
+    signature: UrlSignature,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+) -> Result<Option<Template>, UploadError> {
+    render_viewer(collections, config, id, &signature, false).await
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![viewer_route, viewer_for_browser_route]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    #[get("/", rank = 1)]
+    fn html(_prefers_html: PrefersHtml) -> &'static str {
+        "html"
+    }
+
+    #[get("/", rank = 2)]
+    fn raw() -> &'static str {
+        "raw"
+    }
+
+    fn response_for(client: &Client, uri: &str, accept: Option<&'static str>) -> String {
+        let mut request = client.get(uri.to_string());
+        if let Some(accept) = accept {
+            request = request.header(Header::new("Accept", accept));
+        }
+        request.dispatch().into_string().unwrap()
+    }
+
+    #[test]
+    fn only_browsers_get_html() {
+        let client = Client::tracked(rocket::build().mount("/", routes![html, raw])).unwrap();
+
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert_eq!(response_for(&client, "/", Some(browser)), "html");
+        assert_eq!(response_for(&client, "/?raw", Some(browser)), "raw");
+        assert_eq!(
+            response_for(&client, "/", Some("image/avif,image/webp,*/*")),
+            "raw"
+        );
+        assert_eq!(response_for(&client, "/", Some("*/*")), "raw");
+        assert_eq!(response_for(&client, "/", None), "raw");
+    }
+}
@@ -0,0 +1,392 @@
+//! Who can see an image. Public images can be fetched by anyone who has their
+//! short id, unlisted ones get long random ids so they can't be guessed, and
+//! private ones are only served with a signed url that expires.
+//!
+//! A signed url has `expires` (a unix timestamp) and `sig` in its query, where
+//! `sig` is an HMAC-SHA256 of the image id and `expires` made with
+//! `signing.secret` from the config. Uploading a private image gives back
+//! signed urls, and the API key that uploaded it (or an admin key) can make
+//! new ones with `POST /api/images/<id>/sign`.
+
+use crate::auth::ApiKey;
+use crate::config::{Config, SigningConfig};
+use crate::db;
+use crate::error::UploadError;
+use bson::Document;
+use hmac::{Hmac, Mac};
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{Request, Route, State};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The longest a signed url can work for.
+pub const MAX_URL_TTL_HOURS: u64 = 24 * 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    Public,
+    /// Anyone with the link can see it, but the link can't be guessed
+    Unlisted,
+    /// Only served with a signed url
+    Private,
+}
+
+impl Visibility {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Private => "private",
+        }
+    }
+
+    /// Images uploaded before there were visibilities don't have one, and
+    /// they're public.
+    pub fn from_doc(image_doc: &Document) -> Visibility {
+        match image_doc.get_str("visibility") {
+            Ok("unlisted") => Visibility::Unlisted,
+            Ok("private") => Visibility::Private,
+            _ => Visibility::Public,
+        }
+    }
+
+    /// Check the visibility an upload asked for, which is public if it didn't
+    /// ask. Private images need an API key, since only API keys can make new
+    /// links to them.
+    pub fn requested(
+        requested: Option<&str>,
+        api_key: Option<&ApiKey>,
+        config: &SigningConfig,
+    ) -> Result<Visibility, UploadError> {
+        match requested.map(str::trim).unwrap_or_default() {
+            "" | "public" => Ok(Visibility::Public),
+            "unlisted" => Ok(Visibility::Unlisted),
+            "private" if api_key.is_none() => Err(UploadError::Forbidden(
+                "An API key is needed to upload private images".to_string(),
+            )),
+            "private" if config.secret.is_empty() => Err(UploadError::BadRequest(
+                "Private images aren't enabled on this server".to_string(),
+            )),
+            "private" => Ok(Visibility::Private),
+            other => Err(UploadError::BadRequest(format!(
+                "The visibility must be public, unlisted or private, not {}",
+                other
+            ))),
+        }
+    }
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn url_mac(secret: &str, id: &str, expires: i64) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC keys can be any length");
+    mac.update(id.as_bytes());
+    mac.update(b":");
+    mac.update(expires.to_string().as_bytes());
+    mac
+}
+
+/// The query that makes urls to the image work until `expires`, without the
+/// `?`.
+pub fn signed_query(config: &SigningConfig, id: &str, expires: i64) -> String {
+    let sig = hex::encode(url_mac(&config.secret, id, expires).finalize().into_bytes());
+    format!("expires={}&sig={}", expires, sig)
+}
+
+/// A [`signed_query`] that works for the default amount of time.
+pub fn default_signed_query(config: &SigningConfig, id: &str) -> String {
+    let expires = now_secs() + (config.url_ttl_hours * 60 * 60) as i64;
+    signed_query(config, id, expires)
+}
+
+fn is_valid_signature(config: &SigningConfig, id: &str, expires: i64, sig: &str, now: i64) -> bool {
+    if config.secret.is_empty() || expires < now {
+        return false;
+    }
+    let Ok(sig) = hex::decode(sig) else {
+        return false;
+    };
+    // verify_slice compares in constant time
+    url_mac(&config.secret, id, expires)
+        .verify_slice(&sig)
+        .is_ok()
+}
+
+/// A request guard for the signature in the query of a url to an image,
+/// checked against the id at the end of the path.
+pub enum UrlSignature {
+    /// The url isn't signed
+    Missing,
+    Valid {
+        expires: i64,
+        sig: String,
+    },
+    /// The url is signed, but wrong or expired
+    Invalid,
+}
+
+impl UrlSignature {
+    /// The query to add to links to the image so they keep working.
+    pub fn query(&self) -> Option<String> {
+        match self {
+            UrlSignature::Valid { expires, sig } => {
+                Some(format!("expires={}&sig={}", expires, sig))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UrlSignature {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let (Some(expires), Some(sig)) = (
+            req.query_value::<&str>("expires").and_then(Result::ok),
+            req.query_value::<&str>("sig").and_then(Result::ok),
+        ) else {
+            return Outcome::Success(UrlSignature::Missing);
+        };
+        let (Ok(expires), Some(config), Some(id)) = (
+            expires.parse::<i64>(),
+            req.rocket().state::<Config>(),
+            req.routed_segments(0..).last(),
+        ) else {
+            return Outcome::Success(UrlSignature::Invalid);
+        };
+        if is_valid_signature(&config.signing, id, expires, sig, now_secs()) {
+            Outcome::Success(UrlSignature::Valid {
+                expires,
+                sig: sig.to_string(),
+            })
+        } else {
+            Outcome::Success(UrlSignature::Invalid)
+        }
+    }
+}
+
+/// Make sure the request is allowed to see the image. Private images look
+/// like they don't exist unless the url is signed.
+pub fn check_access(image_doc: &Document, signature: &UrlSignature) -> Result<(), UploadError> {
+    if Visibility::from_doc(image_doc) != Visibility::Private {
+        return Ok(());
+    }
+    match signature {
+        UrlSignature::Valid { .. } => Ok(()),
+        UrlSignature::Missing => Err(UploadError::NotFound("No image found".to_string())),
+        UrlSignature::Invalid => Err(UploadError::Forbidden(
+            "This link is invalid or has expired".to_string(),
+        )),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SignedUrls {
+    url: String,
+    view: String,
+    /// When the urls stop working, as a unix timestamp
+    expires: i64,
+}
+
+/// Whether the API key can make signed urls to the image, which it can if it
+/// uploaded it or is an admin key.
+fn can_sign(image_doc: &Document, api_key: &ApiKey) -> bool {
+    api_key.admin || image_doc.get_str("api_key_id") == Ok(api_key.id.as_str())
+}
+
+/// Make signed urls for an image that work for `hours`, or
+/// `signing.url_ttl_hours` by default.
+#[post("/api/images/<id>/sign?<hours>")]
+async fn sign_route(
+    id: &str,
+    hours: Option<u64>,
+    api_key: ApiKey,
+    collections: &State<db::Collections>,
+    config: &State<Config>,
+) -> Result<Json<SignedUrls>, UploadError> {
+    if config.signing.secret.is_empty() {
+        return Err(UploadError::BadRequest(
+            "Signed urls aren't enabled on this server".to_string(),
+        ));
+    }
+    let hours = hours.unwrap_or(config.signing.url_ttl_hours);
+    if hours == 0 || hours > MAX_URL_TTL_HOURS {
+        return Err(UploadError::BadRequest(format!(
+            "hours must be from 1 to {}",
+            MAX_URL_TTL_HOURS
+        )));
+    }
+    let Some(image_doc) = db::get_image_metadata(&collections.images, id).await? else {
+        return Err(UploadError::NotFound("No image found".to_string()));
+    };
+    if !can_sign(&image_doc, &api_key) {
+        return Err(UploadError::Forbidden(
+            "Only the API key that uploaded the image can sign urls to it".to_string(),
+        ));
+    }
+
+    let expires = now_secs() + (hours * 60 * 60) as i64;
+    let query = signed_query(&config.signing, id, expires);
+    Ok(Json(SignedUrls {
+        url: format!("https://{}/{}?{}", config.host, id, query),
+        view: format!("https://{}/v/{}?{}", config.host, id, query),
+        expires,
+    }))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![sign_route]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signing_config() -> SigningConfig {
+        SigningConfig {
+            secret: "a".repeat(32),
+            ..SigningConfig::default()
+        }
+    }
+
+    fn sig_from_query(query: &str) -> &str {
+        query.split("&sig=").nth(1).unwrap()
+    }
+
+    #[test]
+    fn signatures_are_checked() {
+        let config = signing_config();
+        let query = signed_query(&config, "abc", 1000);
+        assert_eq!(query.split('&').next(), Some("expires=1000"));
+        let sig = sig_from_query(&query);
+
+        assert!(is_valid_signature(&config, "abc", 1000, sig, 999));
+        // expired
+        assert!(!is_valid_signature(&config, "abc", 1000, sig, 1001));
+        // for a different image or time
+        assert!(!is_valid_signature(&config, "abd", 1000, sig, 999));
+        assert!(!is_valid_signature(&config, "abc", 2000, sig, 999));
+        assert!(!is_valid_signature(&config, "abc", 1000, "not hex", 999));
+        // with a different secret
+        let other_config = SigningConfig {
+            secret: "b".repeat(32),
+            ..SigningConfig::default()
+        };
+        assert!(!is_valid_signature(&other_config, "abc", 1000, sig, 999));
+        assert!(!is_valid_signature(
+            &SigningConfig::default(),
+            "abc",
+            1000,
+            sig,
+            999
+        ));
+    }
+
+    #[get("/v/<_id>")]
+    fn signed(_id: &str, signature: UrlSignature) -> &'static str {
+        match signature {
+            UrlSignature::Missing => "missing",
+            UrlSignature::Valid { .. } => "valid",
+            UrlSignature::Invalid => "invalid",
+        }
+    }
+
+    #[test]
+    fn signatures_are_checked_against_the_url() {
+        use rocket::local::blocking::Client;
+
+        let config = Config {
+            signing: signing_config(),
+            ..Config::default()
+        };
+        let query = default_signed_query(&config.signing, "abc");
+        let client =
+            Client::tracked(rocket::build().manage(config).mount("/", routes![signed])).unwrap();
+        let response_for = |uri: String| client.get(uri).dispatch().into_string().unwrap();
+
+        assert_eq!(response_for("/v/abc".to_string()), "missing");
+        assert_eq!(response_for(format!("/v/abc?{}", query)), "valid");
+        assert_eq!(response_for(format!("/v/abd?{}", query)), "invalid");
+        assert_eq!(
+            response_for("/v/abc?expires=1&sig=00".to_string()),
+            "invalid"
+        );
+    }
+
+    #[test]
+    fn private_images_need_a_signature() {
+        let private = bson::doc! {"visibility": "private"};
+        let valid = UrlSignature::Valid {
+            expires: 0,
+            sig: String::new(),
+        };
+        assert!(check_access(&bson::doc! {}, &UrlSignature::Missing).is_ok());
+        assert!(check_access(
+            &bson::doc! {"visibility": "unlisted"},
+            &UrlSignature::Missing
+        )
+        .is_ok());
+        assert!(check_access(&private, &valid).is_ok());
+        assert!(matches!(
+            check_access(&private, &UrlSignature::Missing),
+            Err(UploadError::NotFound(_))
+        ));
+        assert!(matches!(
+            check_access(&private, &UrlSignature::Invalid),
+            Err(UploadError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn only_the_uploader_or_an_admin_can_sign() {
+        let key = |id: &str, admin: bool| ApiKey {
+            id: id.to_string(),
+            name: "team".to_string(),
+            admin,
+        };
+        let image_doc = bson::doc! {"visibility": "private", "api_key_id": "uploader"};
+        assert!(can_sign(&image_doc, &key("uploader", false)));
+        assert!(!can_sign(&image_doc, &key("other", false)));
+        assert!(can_sign(&image_doc, &key("other", true)));
+        // images uploaded without a key
+        assert!(!can_sign(&bson::doc! {}, &key("other", false)));
+    }
+
+    #[test]
+    fn private_uploads_need_an_api_key_and_a_secret() {
+        let api_key = ApiKey {
+            id: "hash".to_string(),
+            name: "test".to_string(),
+            admin: false,
+        };
+        let config = signing_config();
+        assert_eq!(
+            Visibility::requested(None, None, &config).unwrap(),
+            Visibility::Public
+        );
+        assert_eq!(
+            Visibility::requested(Some("unlisted"), None, &config).unwrap(),
+            Visibility::Unlisted
+        );
+        assert!(Visibility::requested(Some("private"), None, &config).is_err());
+        assert!(
+            Visibility::requested(Some("private"), Some(&api_key), &SigningConfig::default())
+                .is_err()
+        );
+        assert_eq!(
+            Visibility::requested(Some("private"), Some(&api_key), &config).unwrap(),
+            Visibility::Private
+        );
+        assert!(Visibility::requested(Some("secret"), Some(&api_key), &config).is_err());
+    }
+}